@@ -0,0 +1,68 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::RngCore;
+
+/// Load the agent's persistent ed25519 identity from its base64-encoded seed,
+/// or generate a fresh one if none exists yet. Callers are expected to persist
+/// back a freshly generated seed so the identity survives restarts.
+pub fn load_or_create_signing_key(seed_b64: &mut Option<String>) -> SigningKey {
+    if let Some(seed) = seed_b64.as_ref() {
+        if let Ok(bytes) = STANDARD.decode(seed) {
+            if let Ok(seed_bytes) = <[u8; 32]>::try_from(bytes.as_slice()) {
+                return SigningKey::from_bytes(&seed_bytes);
+            }
+        }
+    }
+
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    *seed_b64 = Some(STANDARD.encode(key.to_bytes()));
+    key
+}
+
+/// Base64-encoded public key to send in `AgentHello`
+pub fn public_key_b64(key: &SigningKey) -> String {
+    STANDARD.encode(key.verifying_key().to_bytes())
+}
+
+/// Sign a base64-encoded challenge nonce, returning a base64-encoded signature
+pub fn sign_nonce_b64(key: &SigningKey, nonce_b64: &str) -> Result<String, base64::DecodeError> {
+    let nonce = STANDARD.decode(nonce_b64)?;
+    Ok(STANDARD.encode(key.sign(&nonce).to_bytes()))
+}
+
+/// Generate a fresh base64-encoded random nonce for the agent to challenge
+/// the server with, mirroring the nonce the server challenges the agent with
+pub fn generate_nonce_b64() -> String {
+    let mut nonce = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+    STANDARD.encode(nonce)
+}
+
+/// Verify a base64-encoded signature over a base64-encoded message against a
+/// base64-encoded ed25519 public key, used to pin the one server instance an
+/// agent will accept commands from
+pub fn verify_signature_b64(public_key_b64: &str, message_b64: &str, signature_b64: &str) -> bool {
+    let Ok(key_bytes) = STANDARD.decode(public_key_b64) else {
+        return false;
+    };
+    let Ok(key_bytes): Result<[u8; 32], _> = key_bytes.try_into() else {
+        return false;
+    };
+    let Ok(verifying_key) = VerifyingKey::from_bytes(&key_bytes) else {
+        return false;
+    };
+
+    let Ok(message) = STANDARD.decode(message_b64) else {
+        return false;
+    };
+
+    let Ok(sig_bytes) = STANDARD.decode(signature_b64) else {
+        return false;
+    };
+    let Ok(sig_bytes): Result<[u8; 64], _> = sig_bytes.try_into() else {
+        return false;
+    };
+    let signature = Signature::from_bytes(&sig_bytes);
+
+    verifying_key.verify(&message, &signature).is_ok()
+}
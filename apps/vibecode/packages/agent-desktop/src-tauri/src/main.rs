@@ -1,7 +1,11 @@
 // Prevents additional console window on Windows in release
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod identity;
 mod metrics;
+mod output;
+mod queue_store;
+mod runner;
 mod state;
 mod tools;
 mod tray;
@@ -33,6 +37,15 @@ fn get_logs(state: State<AppStateHandle>) -> Vec<LogEntry> {
     state.logs.read().iter().cloned().collect()
 }
 
+/// Get `run_command` failure-reason statistics over the last `last_days` days
+#[tauri::command]
+fn get_task_stats(
+    last_days: i32,
+    state: State<AppStateHandle>,
+) -> Vec<state::TaskReasonStat> {
+    state.get_task_stats(last_days)
+}
+
 /// Get current settings
 #[tauri::command]
 fn get_settings(state: State<AppStateHandle>) -> AgentSettings {
@@ -48,6 +61,7 @@ async fn update_settings(
 ) -> Result<(), String> {
     use tauri_plugin_store::StoreExt;
 
+    state.rebuild_rate_limiter(settings.rate_limit_per_minute);
     *state.settings.write() = settings.clone();
 
     // Persist settings
@@ -83,6 +97,37 @@ fn disconnect(state: State<AppStateHandle>) -> Result<(), String> {
     Ok(())
 }
 
+/// Cancel a running or queued tool request by id, killing its process tree
+/// if it has one
+#[tauri::command]
+fn cancel_command(request_id: String, state: State<AppStateHandle>) -> Result<(), String> {
+    match state.cancel_request(&request_id) {
+        Some(state::CancelOutcome::WasRunning(_)) => {
+            state.decrement_active_commands();
+            state.log_warning(format!("Command {} cancelled by user", request_id));
+        }
+        Some(state::CancelOutcome::WasQueued(_)) => {
+            let working_directory = state.settings.read().working_directory.clone();
+            queue_store::remove_task(&working_directory, &request_id);
+            state.log_warning(format!("Queued command {} cancelled by user", request_id));
+        }
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// Query the lifecycle status of a request by id, so clients can poll for
+/// a final result after the fact instead of only reacting to the live
+/// `ToolResponse` WS message
+#[tauri::command]
+fn get_request_status(
+    request_id: String,
+    state: State<AppStateHandle>,
+) -> Option<state::RequestStatus> {
+    state.get_request_status(&request_id)
+}
+
 /// Select a directory using native dialog
 #[tauri::command]
 async fn select_directory(app: AppHandle) -> Result<Option<String>, String> {
@@ -173,6 +218,42 @@ fn main() {
                 }
             }
 
+            // Ensure we have a persistent ed25519 identity for the
+            // challenge-response handshake, generating and saving one on
+            // first run.
+            {
+                let mut settings = state_clone.settings.read().clone();
+                let mut seed = settings.signing_key_seed.clone();
+                identity::load_or_create_signing_key(&mut seed);
+
+                if settings.signing_key_seed != seed {
+                    settings.signing_key_seed = seed;
+                    *state_clone.settings.write() = settings.clone();
+
+                    if let Ok(store) = app.store("settings.json") {
+                        let _ = store.set("settings", serde_json::to_value(&settings).unwrap());
+                        let _ = store.save();
+                    }
+                }
+            }
+
+            // Replay any tasks left over from a previous crash/restart before
+            // we ever register with the server, so they're redelivered
+            // rather than silently dropped.
+            let working_directory = state_clone.settings.read().working_directory.clone();
+            let recovered = queue_store::load_persisted_tasks(&working_directory);
+            if !recovered.is_empty() {
+                state_clone.log_warning(format!(
+                    "Recovered {} queued task(s) from a previous session",
+                    recovered.len()
+                ));
+                *state_clone.recovered_task_count.write() = recovered.len();
+                for task in recovered {
+                    state_clone.register_request(task.request_id.clone(), task.session_id.clone());
+                    state_clone.enqueue_task(task);
+                }
+            }
+
             // Create system tray
             let _tray = tray::create_tray(&handle)?;
 
@@ -265,10 +346,13 @@ fn main() {
             get_status,
             get_metrics,
             get_logs,
+            get_task_stats,
             get_settings,
             update_settings,
             connect,
             disconnect,
+            cancel_command,
+            get_request_status,
             select_directory,
             check_for_updates,
             install_update,
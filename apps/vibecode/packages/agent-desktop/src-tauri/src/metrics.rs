@@ -1,8 +1,12 @@
-use crate::state::{AppState, SystemMetrics};
+use crate::state::{AgentSettings, AppState, ProcessMetrics, SystemMetrics};
+use parking_lot::Mutex;
+use std::collections::HashSet;
 use std::sync::Arc;
-use sysinfo::System;
+use sysinfo::{Pid, System};
+use tokio::time::Duration;
 
-/// Thresholds for system load
+/// Default thresholds for system load, used to seed `AgentSettings` and
+/// overridable per-user via `max_cpu_percent`/`max_memory_percent`
 pub const MAX_CPU_PERCENT: f64 = 80.0;
 pub const MAX_MEMORY_PERCENT: f64 = 85.0;
 
@@ -22,6 +26,8 @@ pub fn collect_system_metrics(state: &Arc<AppState>) -> SystemMetrics {
 
     let active_commands = state.get_active_commands();
     let queued_tasks = state.get_queue_length() as u32;
+    let running_commands = state.task_runner.running_count() as u32;
+    let (tasks_per_sec, success_rate) = state.get_task_throughput();
 
     SystemMetrics {
         cpu_percent: (cpu_percent * 10.0).round() / 10.0,
@@ -30,12 +36,169 @@ pub fn collect_system_metrics(state: &Arc<AppState>) -> SystemMetrics {
         memory_percent: (memory_percent * 10.0).round() / 10.0,
         active_commands,
         queued_tasks,
+        running_commands,
+        tasks_per_sec: (tasks_per_sec * 100.0).round() / 100.0,
+        success_rate: (success_rate * 1000.0).round() / 1000.0,
     }
 }
 
-/// Check if system is under high load
-pub fn is_system_overloaded(metrics: &SystemMetrics) -> bool {
-    metrics.cpu_percent > MAX_CPU_PERCENT || metrics.memory_percent > MAX_MEMORY_PERCENT
+/// sysinfo refuses to report a meaningful CPU delta faster than this, so
+/// sampling any more often than this just burns a tick for free
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Default, Clone, Copy)]
+struct SamplerStats {
+    peak_cpu_percent: f64,
+    peak_memory_bytes: u64,
+    cpu_sum: f64,
+    samples: u32,
+}
+
+/// Samples CPU and memory usage of a process and all of its descendants on
+/// a fixed interval, so `run_command` can report truthful resource usage
+/// instead of a hardcoded zero.
+pub struct ProcessSampler {
+    stats: Arc<Mutex<SamplerStats>>,
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl ProcessSampler {
+    /// Start sampling the process tree rooted at `pid` in the background
+    pub fn spawn(pid: u32) -> Self {
+        let stats = Arc::new(Mutex::new(SamplerStats::default()));
+        let stats_for_task = stats.clone();
+
+        let handle = tokio::spawn(async move {
+            let root = Pid::from_u32(pid);
+            let mut sys = System::new();
+            let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+
+            loop {
+                interval.tick().await;
+                sys.refresh_processes(sysinfo::ProcessesToUpdate::All, true);
+
+                let (cpu_percent, memory_bytes) = sum_process_tree(&sys, root);
+
+                let mut stats = stats_for_task.lock();
+                stats.cpu_sum += cpu_percent;
+                stats.samples += 1;
+                stats.peak_cpu_percent = stats.peak_cpu_percent.max(cpu_percent);
+                stats.peak_memory_bytes = stats.peak_memory_bytes.max(memory_bytes);
+            }
+        });
+
+        Self { stats, handle }
+    }
+
+    /// Stop sampling and summarize what was observed into a `ProcessMetrics`
+    pub fn finish(self, duration_ms: u64) -> ProcessMetrics {
+        self.handle.abort();
+
+        let stats = *self.stats.lock();
+        let avg_cpu_percent = if stats.samples > 0 {
+            stats.cpu_sum / stats.samples as f64
+        } else {
+            0.0
+        };
+
+        ProcessMetrics {
+            // The peak is more useful than the average for spotting a command
+            // that briefly saturated a core, which an average would hide
+            cpu_percent: (stats.peak_cpu_percent.max(avg_cpu_percent) * 10.0).round() / 10.0,
+            memory_mb: stats.peak_memory_bytes as f64 / 1024.0 / 1024.0,
+            duration_ms,
+        }
+    }
+}
+
+/// Sum CPU usage and memory of `root` and every process descending from it,
+/// walking the tree via each process's `parent()` pointer
+fn sum_process_tree(sys: &System, root: Pid) -> (f64, u64) {
+    let mut cpu_percent = 0.0;
+    let mut memory_bytes = 0u64;
+    let mut visited = HashSet::new();
+    let mut stack = vec![root];
+
+    while let Some(pid) = stack.pop() {
+        if !visited.insert(pid) {
+            continue;
+        }
+
+        if let Some(process) = sys.process(pid) {
+            cpu_percent += process.cpu_usage() as f64;
+            memory_bytes += process.memory();
+        }
+
+        for (child_pid, process) in sys.processes() {
+            if process.parent() == Some(pid) {
+                stack.push(*child_pid);
+            }
+        }
+    }
+
+    (cpu_percent, memory_bytes)
+}
+
+/// Check if system is under high load, against the user-configurable
+/// thresholds in `AgentSettings` rather than a fixed constant
+pub fn is_system_overloaded(metrics: &SystemMetrics, settings: &AgentSettings) -> bool {
+    metrics.cpu_percent > settings.max_cpu_percent
+        || metrics.memory_percent > settings.max_memory_percent
+}
+
+/// Default CPU utilization the dispatch throttle tries to hold the host
+/// near, below the harder `MAX_CPU_PERCENT` queueing cutoff
+pub const TARGET_CPU_PERCENT: f64 = 60.0;
+/// Default ceiling on how long the throttle will delay a single dispatch
+pub const MAX_THROTTLE_DELAY_MS: u64 = 5_000;
+
+/// Smoothing factor for the CPU EWMA: how much weight the latest reading
+/// gets versus the running average, chosen to damp single-sample noise
+/// without lagging a real trend by more than a couple of readings
+const EWMA_ALPHA: f64 = 0.3;
+/// Smallest non-zero delay the throttle will grow from when it first
+/// notices CPU over target, so `last_delay * (measured / target)` has
+/// something to multiply instead of staying stuck at zero
+const MIN_DELAY_STEP_MS: f64 = 50.0;
+/// Factor the delay shrinks by each tick while under target, so dispatch
+/// recovers to full speed a few ticks after load clears rather than instantly
+const DELAY_DECAY: f64 = 0.5;
+
+/// A "tranquilizer" for task dispatch: before starting the next queued task,
+/// `next_delay` computes how long to sleep first so sustained CPU use stays
+/// near `target_cpu_percent` instead of the agent always racing up to
+/// `max_concurrent_commands` regardless of what else is running on the host.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    ewma_cpu_percent: Mutex<f64>,
+    delay_ms: Mutex<f64>,
+}
+
+impl Throttle {
+    /// Fold the latest CPU reading into a short EWMA, grow the dispatch
+    /// delay when that's above `target_cpu_percent`, and shrink it back
+    /// toward zero otherwise. Returns how long to sleep before dispatching.
+    pub fn next_delay(
+        &self,
+        measured_cpu_percent: f64,
+        target_cpu_percent: f64,
+        max_delay_ms: u64,
+    ) -> Duration {
+        let mut ewma = self.ewma_cpu_percent.lock();
+        *ewma = EWMA_ALPHA * measured_cpu_percent + (1.0 - EWMA_ALPHA) * *ewma;
+        let smoothed = *ewma;
+        drop(ewma);
+
+        let mut delay = self.delay_ms.lock();
+        *delay = if target_cpu_percent > 0.0 && smoothed > target_cpu_percent {
+            let baseline = delay.max(MIN_DELAY_STEP_MS);
+            (baseline * (smoothed / target_cpu_percent)).min(max_delay_ms as f64)
+        } else {
+            *delay * DELAY_DECAY
+        };
+
+        Duration::from_millis(*delay as u64)
+    }
 }
 
 /// Get basic system info for registration
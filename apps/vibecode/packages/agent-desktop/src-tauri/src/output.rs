@@ -0,0 +1,72 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Which stream a chunk of command output came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// A chunk of incrementally-produced command output, ordered within its
+/// request by `seq`
+#[derive(Debug, Clone)]
+pub struct OutputChunk {
+    pub request_id: String,
+    pub stream: OutputStream,
+    pub seq: u64,
+    pub bytes: Vec<u8>,
+    pub at: chrono::DateTime<chrono::Utc>,
+}
+
+/// How many chunks a slow consumer can fall behind before `push` starts
+/// blocking the producer, so a stalled WebSocket can't let a chatty command
+/// balloon memory
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Producer half of a request's output channel, held by the running
+/// command so it can push chunks as stdout/stderr are read
+#[derive(Debug, Clone)]
+pub struct OutputProducer {
+    request_id: String,
+    tx: mpsc::Sender<OutputChunk>,
+    next_seq: Arc<AtomicU64>,
+}
+
+impl OutputProducer {
+    /// Push a chunk of output, applying backpressure if the consumer on the
+    /// other end hasn't kept up
+    pub async fn push(&self, stream: OutputStream, bytes: Vec<u8>) {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let chunk = OutputChunk {
+            request_id: self.request_id.clone(),
+            stream,
+            seq,
+            bytes,
+            at: chrono::Utc::now(),
+        };
+        // A closed receiver just means nothing is subscribed to forward
+        // this request's output anymore; not a producer-side error
+        let _ = self.tx.send(chunk).await;
+    }
+}
+
+/// Consumer half, handed to whoever forwards chunks onward (the WebSocket
+/// write loop)
+pub struct OutputSubscriber {
+    pub rx: mpsc::Receiver<OutputChunk>,
+}
+
+/// Open a bounded producer/subscriber pair for one request's output
+pub fn channel(request_id: String) -> (OutputProducer, OutputSubscriber) {
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let producer = OutputProducer {
+        request_id,
+        tx,
+        next_seq: Arc::new(AtomicU64::new(0)),
+    };
+    (producer, OutputSubscriber { rx })
+}
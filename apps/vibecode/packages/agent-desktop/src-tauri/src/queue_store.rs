@@ -0,0 +1,96 @@
+use crate::state::QueuedTask;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Directory used to persist queued tasks so they survive an agent crash or
+/// restart, keyed off the agent's working directory.
+fn queue_dir(working_dir: &str) -> PathBuf {
+    Path::new(working_dir).join(".vibecode-agent").join("queue")
+}
+
+/// Append-only log of tasks that exhausted their retry budget, kept for
+/// later inspection rather than silently dropped
+fn dead_letter_path(working_dir: &str) -> PathBuf {
+    queue_dir(working_dir).join("dead-letter.log")
+}
+
+/// Persist a queued task to disk so it can be redelivered if the agent
+/// crashes before executing it
+pub fn persist_task(working_dir: &str, task: &QueuedTask) {
+    let dir = queue_dir(working_dir);
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        log::warn!("Failed to create queue store dir: {}", e);
+        return;
+    }
+
+    let path = dir.join(format!("{}.json", task.request_id));
+    match serde_json::to_vec(task) {
+        Ok(bytes) => {
+            if let Err(e) = std::fs::write(&path, bytes) {
+                log::warn!("Failed to persist queued task {}: {}", task.request_id, e);
+            }
+        }
+        Err(e) => log::warn!("Failed to serialize queued task {}: {}", task.request_id, e),
+    }
+}
+
+/// Remove a persisted task record, e.g. once it has completed successfully
+pub fn remove_task(working_dir: &str, request_id: &str) {
+    let path = queue_dir(working_dir).join(format!("{}.json", request_id));
+    let _ = std::fs::remove_file(path);
+}
+
+/// Record a task that exceeded its max-attempts threshold, then drop its
+/// pending-retry record so it is not picked up again
+pub fn append_dead_letter(working_dir: &str, task: &QueuedTask, reason: &str) {
+    remove_task(working_dir, &task.request_id);
+
+    let dir = queue_dir(working_dir);
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        log::warn!("Failed to create queue store dir: {}", e);
+        return;
+    }
+
+    let entry = serde_json::json!({
+        "requestId": task.request_id,
+        "tool": task.tool,
+        "attempts": task.attempts,
+        "reason": reason,
+        "failedAt": chrono::Utc::now(),
+    });
+
+    let Ok(mut line) = serde_json::to_vec(&entry) else {
+        return;
+    };
+    line.push(b'\n');
+
+    if let Ok(mut file) = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dead_letter_path(working_dir))
+    {
+        let _ = file.write_all(&line);
+    }
+}
+
+/// Load any tasks left over from a previous run, oldest first, marking each
+/// as redelivered so the server knows it may already have seen it
+pub fn load_persisted_tasks(working_dir: &str) -> Vec<QueuedTask> {
+    let dir = queue_dir(working_dir);
+    let Ok(entries) = std::fs::read_dir(&dir) else {
+        return Vec::new();
+    };
+
+    let mut tasks: Vec<QueuedTask> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| std::fs::read(e.path()).ok())
+        .filter_map(|bytes| serde_json::from_slice::<QueuedTask>(&bytes).ok())
+        .map(|mut task| {
+            task.redelivered = true;
+            task
+        })
+        .collect();
+
+    tasks.sort_by_key(|t| t.queued_at);
+    tasks
+}
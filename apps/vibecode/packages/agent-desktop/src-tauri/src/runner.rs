@@ -0,0 +1,83 @@
+use futures_util::FutureExt;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::{AbortHandle, JoinSet};
+
+/// Supervises the set of currently-executing tool tasks. Replacing ad-hoc
+/// `tokio::spawn` calls with a shared `JoinSet` means a disconnect can
+/// deterministically wait for in-flight work to finish (or abort it after a
+/// timeout) instead of leaking tasks across reconnects.
+pub struct TaskRunner {
+    tasks: parking_lot::Mutex<JoinSet<()>>,
+    shutdown_tx: watch::Sender<bool>,
+}
+
+impl std::fmt::Debug for TaskRunner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TaskRunner")
+            .field("running", &self.running_count())
+            .finish()
+    }
+}
+
+impl Default for TaskRunner {
+    fn default() -> Self {
+        let (shutdown_tx, _) = watch::channel(false);
+        Self {
+            tasks: parking_lot::Mutex::new(JoinSet::new()),
+            shutdown_tx,
+        }
+    }
+}
+
+impl TaskRunner {
+    /// Spawn a future onto the supervised set, returning an abort handle the
+    /// caller can register for server-initiated cancellation.
+    pub fn spawn<F>(&self, future: F) -> AbortHandle
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.lock().spawn(future)
+    }
+
+    /// Number of tool executions currently tracked by the runner
+    pub fn running_count(&self) -> usize {
+        let mut tasks = self.tasks.lock();
+        // `JoinSet::len` keeps counting a finished task until its result is
+        // joined, so reap whatever has already completed first
+        while let Some(Some(_)) = tasks.join_next().now_or_never() {}
+        tasks.len()
+    }
+
+    /// Subscribe to the shutdown flag, so callers with their own pull loop
+    /// (queue workers picking up new tasks) can stop admitting new work as
+    /// soon as shutdown starts, while what's already running still gets the
+    /// usual grace period before `shutdown()` aborts it.
+    pub fn shutdown_signal(&self) -> watch::Receiver<bool> {
+        self.shutdown_tx.subscribe()
+    }
+
+    /// Signal shutdown, then wait for every tracked task to finish up to
+    /// `timeout`, aborting whatever is left afterward
+    pub async fn shutdown(&self, timeout: Duration) {
+        let _ = self.shutdown_tx.send(true);
+
+        // JoinSet::join_next needs `&mut self`, which can't be held across an
+        // await while also locked, so take ownership of the set under the lock.
+        let mut tasks = std::mem::take(&mut *self.tasks.lock());
+
+        let drained = tokio::time::timeout(timeout, async {
+            while tasks.join_next().await.is_some() {}
+        })
+        .await
+        .is_ok();
+
+        if !drained {
+            tasks.abort_all();
+            while tasks.join_next().await.is_some() {}
+        }
+
+        let _ = self.shutdown_tx.send(false);
+    }
+}
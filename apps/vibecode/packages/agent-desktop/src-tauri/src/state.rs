@@ -1,7 +1,23 @@
+use governor::clock::Clock;
+use governor::{Quota, RateLimiter};
 use parking_lot::RwLock;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroU32;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Per-session token bucket, keyed by `session_id`, used to throttle a
+/// misbehaving or compromised session rather than the agent as a whole
+pub type SessionRateLimiter = governor::DefaultKeyedRateLimiter<String>;
+
+fn build_rate_limiter(requests_per_minute: u32) -> Arc<SessionRateLimiter> {
+    let quota = Quota::per_minute(NonZeroU32::new(requests_per_minute.max(1)).unwrap());
+    Arc::new(RateLimiter::keyed(quota))
+}
 
 /// Connection status for the agent
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -20,6 +36,61 @@ impl Default for ConnectionStatus {
     }
 }
 
+/// How the delay between reconnect attempts is computed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReconnectStrategy {
+    /// Always wait `reconnect_base_delay_ms` between attempts
+    Constant,
+    /// Wait `min(base_delay * 2^attempt, max_delay)`, then apply full jitter
+    Exponential,
+}
+
+impl Default for ReconnectStrategy {
+    fn default() -> Self {
+        Self::Exponential
+    }
+}
+
+/// Filesystem and command allowlists that bound what `execute_tool` is
+/// willing to touch, so a compromised or misbehaving server can't read
+/// arbitrary files or run arbitrary commands on the host.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScopeSettings {
+    /// Root directories tools may read/write under. Empty means "just the
+    /// connection's working directory".
+    pub allowed_roots: Vec<String>,
+    /// Literal program names or glob patterns `run_command` may execute.
+    /// Empty means no restriction (today's behavior).
+    pub allowed_commands: Vec<String>,
+}
+
+impl ScopeSettings {
+    /// Whether the leading program token of `command` matches the allowlist.
+    /// An empty allowlist permits anything.
+    pub fn is_command_allowed(&self, command: &str) -> bool {
+        if self.allowed_commands.is_empty() {
+            return true;
+        }
+
+        let program = match command.split_whitespace().next() {
+            Some(p) => p,
+            None => return false,
+        };
+        let program_name = std::path::Path::new(program)
+            .file_name()
+            .and_then(|f| f.to_str())
+            .unwrap_or(program);
+
+        self.allowed_commands.iter().any(|pattern| {
+            glob::Pattern::new(pattern)
+                .map(|p| p.matches(program_name))
+                .unwrap_or(pattern == program_name)
+        })
+    }
+}
+
 /// Agent settings stored persistently
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -29,6 +100,40 @@ pub struct AgentSettings {
     pub max_concurrent_commands: u32,
     pub auto_start_on_login: bool,
     pub auto_connect_on_launch: bool,
+    pub reconnect_strategy: ReconnectStrategy,
+    pub reconnect_base_delay_ms: u64,
+    pub reconnect_max_delay_ms: u64,
+    /// Number of consecutive missed pongs tolerated before the connection
+    /// is considered stale and forcibly closed
+    pub missed_heartbeat_limit: u32,
+    /// Maximum `ToolRequest`s a single session may issue per minute before
+    /// being throttled
+    pub rate_limit_per_minute: u32,
+    /// Base64-encoded ed25519 seed identifying this agent to servers during
+    /// the challenge-response handshake. Generated on first run.
+    pub signing_key_seed: Option<String>,
+    /// Base64-encoded ed25519 public key of the one server instance this
+    /// agent will accept commands from. When set, the handshake rejects any
+    /// server that can't prove possession of the matching private key; when
+    /// unset (the default), the handshake only authenticates the agent to
+    /// the server and any server is accepted, same as before this setting
+    /// existed.
+    #[serde(default)]
+    pub trusted_server_public_key: Option<String>,
+    /// Filesystem roots and command allowlist that bound `execute_tool`
+    pub scope: ScopeSettings,
+    /// CPU usage percentage above which new `run_command` tasks are queued
+    /// instead of executed, to avoid saturating a weaker machine
+    pub max_cpu_percent: f64,
+    /// Memory usage percentage above which new `run_command` tasks are
+    /// queued instead of executed
+    pub max_memory_percent: f64,
+    /// CPU utilization the dispatch throttle tries to hold the host near by
+    /// delaying task starts, distinct from `max_cpu_percent`'s hard queue
+    /// cutoff
+    pub target_cpu_percent: f64,
+    /// Upper bound on how long the throttle will delay a single dispatch
+    pub max_delay_ms: u64,
 }
 
 impl Default for AgentSettings {
@@ -41,6 +146,18 @@ impl Default for AgentSettings {
             max_concurrent_commands: 5,
             auto_start_on_login: true,  // Default to auto-start so agent is always available
             auto_connect_on_launch: true,
+            reconnect_strategy: ReconnectStrategy::Exponential,
+            reconnect_base_delay_ms: 1000,
+            reconnect_max_delay_ms: 60_000,
+            missed_heartbeat_limit: 2,
+            rate_limit_per_minute: 120,
+            signing_key_seed: None,
+            trusted_server_public_key: None,
+            scope: ScopeSettings::default(),
+            max_cpu_percent: crate::metrics::MAX_CPU_PERCENT,
+            max_memory_percent: crate::metrics::MAX_MEMORY_PERCENT,
+            target_cpu_percent: crate::metrics::TARGET_CPU_PERCENT,
+            max_delay_ms: crate::metrics::MAX_THROTTLE_DELAY_MS,
         }
     }
 }
@@ -55,6 +172,18 @@ pub struct SystemMetrics {
     pub memory_percent: f64,
     pub active_commands: u32,
     pub queued_tasks: u32,
+    /// Tool executions the supervised `TaskRunner` is actually tracking
+    /// right now. In steady state this tracks `active_commands` closely,
+    /// but the two are derived independently (one from worker reservations,
+    /// the other from the runner's own `JoinSet`), so a persistent gap
+    /// between them is a sign something is stuck rather than dispatched.
+    pub running_commands: u32,
+    /// Rolling `run_command` completions/sec over the last minute, from
+    /// `AppState::get_task_throughput`
+    pub tasks_per_sec: f64,
+    /// Rolling `run_command` success rate over the last minute (1.0 if none
+    /// completed in that window)
+    pub success_rate: f64,
 }
 
 /// A log entry for the activity log
@@ -85,7 +214,7 @@ pub struct ProcessMetrics {
 }
 
 /// Queued task awaiting execution
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QueuedTask {
     pub request_id: String,
     pub session_id: String,
@@ -93,6 +222,117 @@ pub struct QueuedTask {
     pub input: serde_json::Value,
     pub cwd: String,
     pub queued_at: chrono::DateTime<chrono::Utc>,
+    /// True when this task was loaded back from the on-disk queue store
+    /// after a crash or restart, rather than freshly enqueued
+    #[serde(default)]
+    pub redelivered: bool,
+    /// Number of times this task has already been attempted and failed
+    #[serde(default)]
+    pub attempts: u32,
+    /// Earliest time this task is eligible to run again. `None` means it's
+    /// ready immediately; set after a failed attempt to back off retries.
+    #[serde(default)]
+    pub next_attempt_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Higher values are injected at the front of their queue shard instead
+    /// of the back, so they're picked up ahead of ordinary work
+    #[serde(default)]
+    pub priority: i32,
+}
+
+/// A handle to a currently-executing task, kept so a server-initiated
+/// cancellation can abort it and still report back which session it belonged to
+#[derive(Debug)]
+pub struct TaskHandle {
+    pub session_id: String,
+    pub abort_handle: tokio::task::AbortHandle,
+}
+
+/// Lifecycle status of a request tracked in `PendingRequests`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestStatus {
+    Queued,
+    Running,
+    Completed,
+    Cancelled,
+}
+
+/// Tracked lifecycle of a single request, from the moment it's admitted
+/// (queued or run immediately) until it's cancelled or finishes
+#[derive(Debug, Clone)]
+pub struct RequestState {
+    pub session_id: String,
+    pub status: RequestStatus,
+    pub started_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Tripped by `cancel_request` for a request that's already running, so
+    /// the command executor can notice and give up early instead of relying
+    /// solely on the hard `AbortHandle::abort` to stop it
+    pub cancel_flag: Arc<std::sync::atomic::AtomicBool>,
+}
+
+/// What kind of in-flight work `cancel_request` found and stopped
+#[derive(Debug)]
+pub enum CancelOutcome {
+    WasRunning(String),
+    WasQueued(QueuedTask),
+}
+
+/// Maximum number of finished requests kept in `completed_requests` for
+/// clients to query final status after the fact, mirroring the `logs` ring
+const COMPLETED_REQUESTS_CAPACITY: usize = 200;
+
+/// Why a `run_command` execution ended, recorded so the server can chart
+/// failure reasons instead of just seeing activity-log prose
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum TaskOutcome {
+    Success,
+    Timeout,
+    NonZeroExit { code: i32 },
+    Killed,
+    SpawnError,
+    Cancelled,
+}
+
+impl TaskOutcome {
+    /// Stable grouping key for `get_task_stats`, independent of
+    /// `NonZeroExit`'s payload so every non-zero exit code groups together
+    fn reason_key(&self) -> &'static str {
+        match self {
+            TaskOutcome::Success => "success",
+            TaskOutcome::Timeout => "timeout",
+            TaskOutcome::NonZeroExit { .. } => "non_zero_exit",
+            TaskOutcome::Killed => "killed",
+            TaskOutcome::SpawnError => "spawn_error",
+            TaskOutcome::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// One recorded terminal outcome, timestamped so `get_task_stats` can window
+/// by age
+#[derive(Debug, Clone)]
+struct TaskOutcomeRecord {
+    outcome: TaskOutcome,
+    at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Maximum number of recent outcomes kept for `get_task_stats`, mirroring
+/// the other bounded rings on `AppState`
+const TASK_HISTORY_CAPACITY: usize = 500;
+
+/// Window (in seconds) `get_task_throughput` rolls over to produce
+/// `tasks_per_sec` and `success_rate`
+const THROUGHPUT_WINDOW_SECS: i64 = 60;
+
+/// Aggregated count of a single outcome reason within a requested time
+/// window, as returned by `get_task_stats`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskReasonStat {
+    pub reason: String,
+    pub count: u64,
+    pub last_seen: chrono::DateTime<chrono::Utc>,
 }
 
 /// Application state shared across the app
@@ -103,22 +343,79 @@ pub struct AppState {
     pub metrics: RwLock<SystemMetrics>,
     pub logs: RwLock<VecDeque<LogEntry>>,
     pub active_commands: RwLock<u32>,
-    pub task_queue: RwLock<VecDeque<QueuedTask>>,
+    /// Queue shards, one per worker by default: the count is fixed when
+    /// `AppState` is created (from the default `max_concurrent_commands`),
+    /// and a worker whose index is past the last shard shares shard
+    /// `home % len`. Each worker owns a shard as its local deque: it pops
+    /// its own shard first and only scans siblings - stealing from them -
+    /// once its own shard is empty, so no single shard's lock is hotter
+    /// than the rest. For queued work, the `active_commands` slot is
+    /// reserved by the worker that dequeues the task
+    /// (`try_reserve_active_slot`), not at enqueue time.
+    pub task_queue: Vec<RwLock<VecDeque<QueuedTask>>>,
+    /// Rotates which shard the next enqueued task is injected into, so
+    /// injection pressure is spread evenly across workers' shards
+    next_queue_shard: AtomicUsize,
+    /// Wakes idle workers as soon as a task is enqueued, instead of making
+    /// every worker poll on a fixed interval to notice new work
+    pub queue_notify: Notify,
     pub agent_id: RwLock<Option<String>>,
     pub is_connected: RwLock<bool>,
+    pub running_tasks: RwLock<HashMap<String, TaskHandle>>,
+    /// OS process group id of a running `run_command` invocation, keyed by
+    /// request id, so cancellation can kill the whole tree rather than just
+    /// aborting the Rust future awaiting it
+    pub running_processes: RwLock<HashMap<String, u32>>,
+    /// Lifecycle status of every request that's currently queued or running
+    pub pending_requests: RwLock<HashMap<String, RequestState>>,
+    /// Bounded ring of recently finished requests, so a client can still
+    /// look up the final status of a request after it's no longer pending
+    pub completed_requests: RwLock<VecDeque<(String, RequestState)>>,
+    /// Producer half of each in-flight `run_command`'s output-streaming
+    /// channel, keyed by request id, so the command itself can look up
+    /// where to push stdout/stderr chunks as it runs
+    pub output_producers: RwLock<HashMap<String, crate::output::OutputProducer>>,
+    /// Number of queued tasks recovered from disk on this startup, reported
+    /// once on the next `AgentRegister` then reset to zero
+    pub recovered_task_count: RwLock<usize>,
+    pub rate_limiter: RwLock<Arc<SessionRateLimiter>>,
+    pub task_runner: crate::runner::TaskRunner,
+    /// Holds back task dispatch to keep host CPU usage near
+    /// `target_cpu_percent` instead of bursting up to `max_concurrent_commands`
+    /// the instant the machine has any free worker slot
+    pub throttle: crate::metrics::Throttle,
+    /// Bounded history of recent `run_command` terminal outcomes, queried by
+    /// `get_task_stats` and `get_task_throughput`
+    task_history: RwLock<VecDeque<TaskOutcomeRecord>>,
 }
 
 impl Default for AppState {
     fn default() -> Self {
+        let settings = AgentSettings::default();
+        let rate_limiter = build_rate_limiter(settings.rate_limit_per_minute);
+        let shard_count = settings.max_concurrent_commands.max(1) as usize;
+
         Self {
             status: RwLock::new(ConnectionStatus::Disconnected),
-            settings: RwLock::new(AgentSettings::default()),
+            settings: RwLock::new(settings),
             metrics: RwLock::new(SystemMetrics::default()),
             logs: RwLock::new(VecDeque::with_capacity(1000)),
             active_commands: RwLock::new(0),
-            task_queue: RwLock::new(VecDeque::new()),
+            task_queue: (0..shard_count).map(|_| RwLock::new(VecDeque::new())).collect(),
+            next_queue_shard: AtomicUsize::new(0),
+            queue_notify: Notify::new(),
             agent_id: RwLock::new(None),
             is_connected: RwLock::new(false),
+            running_tasks: RwLock::new(HashMap::new()),
+            running_processes: RwLock::new(HashMap::new()),
+            pending_requests: RwLock::new(HashMap::new()),
+            completed_requests: RwLock::new(VecDeque::new()),
+            output_producers: RwLock::new(HashMap::new()),
+            recovered_task_count: RwLock::new(0),
+            rate_limiter: RwLock::new(rate_limiter),
+            task_runner: crate::runner::TaskRunner::default(),
+            throttle: crate::metrics::Throttle::default(),
+            task_history: RwLock::new(VecDeque::new()),
         }
     }
 }
@@ -192,17 +489,318 @@ impl AppState {
         *self.active_commands.read()
     }
 
+    /// Atomically check-and-increment the active-command count under a
+    /// single write lock, so two callers racing the same stale
+    /// `get_active_commands()` read (e.g. two dispatches both waiting out a
+    /// throttle delay) can't both believe they reserved the same slot
+    pub fn try_reserve_active_slot(&self, max_concurrent: u32) -> bool {
+        let mut count = self.active_commands.write();
+        if *count >= max_concurrent {
+            false
+        } else {
+            *count += 1;
+            true
+        }
+    }
+
+    /// Injects `task` into the next shard in rotation, at the front if it's
+    /// high-priority so it's dequeued ahead of ordinary work in that shard,
+    /// then wakes a worker that might be idle waiting for exactly this
     pub fn enqueue_task(&self, task: QueuedTask) -> usize {
-        let mut queue = self.task_queue.write();
-        queue.push_back(task);
-        queue.len()
+        let shard = self.next_queue_shard.fetch_add(1, Ordering::Relaxed) % self.task_queue.len();
+        let mut queue = self.task_queue[shard].write();
+        if task.priority > 0 {
+            queue.push_front(task);
+        } else {
+            queue.push_back(task);
+        }
+        drop(queue);
+        self.queue_notify.notify_one();
+        self.get_queue_length()
     }
 
-    pub fn dequeue_task(&self) -> Option<QueuedTask> {
-        self.task_queue.write().pop_front()
+    /// Pop the first task in queue order that's ready to run for the worker
+    /// owning shard `home`, skipping over any still backing off from a
+    /// previous failed attempt. Tries `home` first; if it's empty (or
+    /// everything in it is backing off), steals from the other shards
+    /// starting at a randomly chosen sibling so thieves don't all pile onto
+    /// the same victim in lockstep.
+    pub fn dequeue_task_for(&self, home: usize) -> Option<QueuedTask> {
+        let n = self.task_queue.len();
+        let home = home % n;
+        let steal_start = if n > 1 {
+            rand::thread_rng().gen_range(1..n)
+        } else {
+            0
+        };
+        let now = chrono::Utc::now();
+
+        for offset in 0..n {
+            let shard = if offset == 0 {
+                home
+            } else {
+                (home + 1 + (steal_start - 1 + offset - 1) % (n - 1)) % n
+            };
+            let mut queue = self.task_queue[shard].write();
+            if let Some(pos) = queue
+                .iter()
+                .position(|t| t.next_attempt_at.map_or(true, |at| at <= now))
+            {
+                return queue.remove(pos);
+            }
+        }
+        None
     }
 
     pub fn get_queue_length(&self) -> usize {
-        self.task_queue.read().len()
+        self.task_queue.iter().map(|shard| shard.read().len()).sum()
+    }
+
+    /// Remove and return a still-queued task by request id, e.g. when it is cancelled
+    /// before it ever started running
+    pub fn remove_queued_task(&self, request_id: &str) -> Option<QueuedTask> {
+        for shard in &self.task_queue {
+            let mut queue = shard.write();
+            if let Some(pos) = queue.iter().position(|t| t.request_id == request_id) {
+                return queue.remove(pos);
+            }
+        }
+        None
+    }
+
+    pub fn register_task_handle(&self, request_id: String, session_id: String, abort_handle: tokio::task::AbortHandle) {
+        self.running_tasks
+            .write()
+            .insert(request_id, TaskHandle { session_id, abort_handle });
+    }
+
+    pub fn remove_task_handle(&self, request_id: &str) -> Option<TaskHandle> {
+        self.running_tasks.write().remove(request_id)
+    }
+
+    /// Record the OS process group spawned for `request_id`'s `run_command`,
+    /// so it can be killed on cancellation even though aborting the Rust
+    /// future that awaits it would otherwise leave it running
+    pub fn register_process(&self, request_id: String, pgid: u32) {
+        self.running_processes.write().insert(request_id, pgid);
+    }
+
+    pub fn remove_process(&self, request_id: &str) -> Option<u32> {
+        self.running_processes.write().remove(request_id)
+    }
+
+    /// Abort a running task by request id, returning its session id if one was found
+    pub fn cancel_running_task(&self, request_id: &str) -> Option<String> {
+        if let Some(req) = self.pending_requests.read().get(request_id) {
+            req.cancel_flag.store(true, Ordering::SeqCst);
+        }
+
+        let handle = self.remove_task_handle(request_id)?;
+        handle.abort_handle.abort();
+
+        if let Some(pgid) = self.remove_process(request_id) {
+            crate::tools::kill_process_group(pgid);
+        }
+
+        Some(handle.session_id)
+    }
+
+    /// Register a request as `Queued`, to be observed or cancelled via
+    /// `pending_requests` before it's ever picked up for execution
+    pub fn register_request(&self, request_id: String, session_id: String) {
+        self.pending_requests.write().insert(
+            request_id,
+            RequestState {
+                session_id,
+                status: RequestStatus::Queued,
+                started_at: None,
+                cancel_flag: Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            },
+        );
+    }
+
+    /// Mark a previously-registered request as having started execution
+    pub fn mark_running(&self, request_id: &str) {
+        if let Some(req) = self.pending_requests.write().get_mut(request_id) {
+            req.status = RequestStatus::Running;
+            req.started_at = Some(chrono::Utc::now());
+        }
+    }
+
+    /// Move a request back to `Queued`, e.g. when a failed attempt is
+    /// re-enqueued for a backoff retry
+    pub fn mark_requeued(&self, request_id: &str) {
+        if let Some(req) = self.pending_requests.write().get_mut(request_id) {
+            req.status = RequestStatus::Queued;
+            req.started_at = None;
+        }
+    }
+
+    /// Move a request out of `pending_requests` and into the bounded
+    /// `completed_requests` ring, tagged `Cancelled` or `Completed`
+    pub fn complete_request(&self, request_id: &str, cancelled: bool) {
+        let Some(mut req) = self.pending_requests.write().remove(request_id) else {
+            return;
+        };
+        req.status = if cancelled {
+            RequestStatus::Cancelled
+        } else {
+            RequestStatus::Completed
+        };
+
+        let mut completed = self.completed_requests.write();
+        completed.push_back((request_id.to_string(), req));
+        while completed.len() > COMPLETED_REQUESTS_CAPACITY {
+            completed.pop_front();
+        }
+    }
+
+    /// Cancel a request by id, stopping it however is appropriate for
+    /// whichever state it's currently in
+    pub fn cancel_request(&self, request_id: &str) -> Option<CancelOutcome> {
+        if let Some(session_id) = self.cancel_running_task(request_id) {
+            self.complete_request(request_id, true);
+            return Some(CancelOutcome::WasRunning(session_id));
+        }
+
+        if let Some(task) = self.remove_queued_task(request_id) {
+            self.complete_request(request_id, true);
+            return Some(CancelOutcome::WasQueued(task));
+        }
+
+        None
+    }
+
+    /// Look up the current status of a request, checking in-flight work
+    /// first and falling back to the recently-completed ring
+    pub fn get_request_status(&self, request_id: &str) -> Option<RequestStatus> {
+        if let Some(req) = self.pending_requests.read().get(request_id) {
+            return Some(req.status);
+        }
+
+        self.completed_requests
+            .read()
+            .iter()
+            .find(|(id, _)| id == request_id)
+            .map(|(_, req)| req.status)
+    }
+
+    /// Whether a running request's cancel flag has been tripped, polled by
+    /// the command executor so it can give up early
+    pub fn is_request_cancelled(&self, request_id: &str) -> bool {
+        self.pending_requests
+            .read()
+            .get(request_id)
+            .map(|req| req.cancel_flag.load(Ordering::SeqCst))
+            .unwrap_or(false)
+    }
+
+    /// Record a terminal `run_command` outcome, evicting the oldest entry
+    /// once the bounded history is full
+    pub fn record_task_outcome(&self, outcome: TaskOutcome) {
+        let mut history = self.task_history.write();
+        if history.len() >= TASK_HISTORY_CAPACITY {
+            history.pop_front();
+        }
+        history.push_back(TaskOutcomeRecord {
+            outcome,
+            at: chrono::Utc::now(),
+        });
+    }
+
+    /// Aggregate recorded outcomes from the last `last_days` days, grouped
+    /// by reason, for the server UI to chart
+    pub fn get_task_stats(&self, last_days: i32) -> Vec<TaskReasonStat> {
+        let cutoff = chrono::Utc::now() - chrono::Duration::days(last_days.max(0) as i64);
+
+        let mut by_reason: HashMap<&'static str, TaskReasonStat> = HashMap::new();
+        for record in self.task_history.read().iter().filter(|r| r.at >= cutoff) {
+            let key = record.outcome.reason_key();
+            let stat = by_reason.entry(key).or_insert_with(|| TaskReasonStat {
+                reason: key.to_string(),
+                count: 0,
+                last_seen: record.at,
+            });
+            stat.count += 1;
+            stat.last_seen = stat.last_seen.max(record.at);
+        }
+
+        let mut stats: Vec<_> = by_reason.into_values().collect();
+        stats.sort_by(|a, b| b.count.cmp(&a.count));
+        stats
+    }
+
+    /// Rolling completions/sec and success rate over the trailing
+    /// `THROUGHPUT_WINDOW_SECS`, folded into `SystemMetrics` so the UI gets
+    /// them without a separate poll
+    pub fn get_task_throughput(&self) -> (f64, f64) {
+        let cutoff = chrono::Utc::now() - chrono::Duration::seconds(THROUGHPUT_WINDOW_SECS);
+        let history = self.task_history.read();
+        let recent: Vec<_> = history.iter().filter(|r| r.at >= cutoff).collect();
+
+        let tasks_per_sec = recent.len() as f64 / THROUGHPUT_WINDOW_SECS as f64;
+        let success_rate = if recent.is_empty() {
+            1.0
+        } else {
+            recent
+                .iter()
+                .filter(|r| matches!(r.outcome, TaskOutcome::Success))
+                .count() as f64
+                / recent.len() as f64
+        };
+
+        (tasks_per_sec, success_rate)
+    }
+
+    /// Open a bounded output-streaming channel for `request_id`, returning
+    /// the subscriber half for whoever forwards chunks onward (the
+    /// WebSocket write loop). The producer half is kept here so
+    /// `run_command` can look it up by request id and push into it.
+    pub fn open_output_channel(&self, request_id: &str) -> crate::output::OutputSubscriber {
+        let (producer, subscriber) = crate::output::channel(request_id.to_string());
+        self.output_producers
+            .write()
+            .insert(request_id.to_string(), producer);
+        subscriber
+    }
+
+    /// Look up the output producer for a running command, so it can push
+    /// stdout/stderr chunks as they're read
+    pub fn output_producer(&self, request_id: &str) -> Option<crate::output::OutputProducer> {
+        self.output_producers.read().get(request_id).cloned()
+    }
+
+    /// Close a request's output channel once the command has finished
+    pub fn close_output_channel(&self, request_id: &str) {
+        self.output_producers.write().remove(request_id);
+    }
+
+    /// Take the recovered-task count, resetting it to zero so it is only
+    /// reported once per process lifetime
+    pub fn take_recovered_task_count(&self) -> usize {
+        std::mem::replace(&mut *self.recovered_task_count.write(), 0)
+    }
+
+    /// Rebuild the rate limiter, e.g. after `rate_limit_per_minute` changes
+    /// via `update_settings`
+    pub fn rebuild_rate_limiter(&self, requests_per_minute: u32) {
+        *self.rate_limiter.write() = build_rate_limiter(requests_per_minute);
+    }
+
+    /// Check whether `session_id` is still within its quota. On success the
+    /// request consumes one token; on failure returns how long the caller
+    /// should wait before retrying.
+    pub fn check_rate_limit(&self, session_id: &str) -> Result<(), Duration> {
+        let limiter = self.rate_limiter.read().clone();
+        let clock = governor::clock::QuantaClock::default();
+        limiter
+            .check_key(&session_id.to_string())
+            .map_err(|not_until| not_until.wait_time_from(clock.now()))
+    }
+
+    /// Drop rate-limiter entries for sessions that haven't made a request
+    /// recently, so memory doesn't grow unbounded
+    pub fn expire_idle_rate_limits(&self) {
+        self.rate_limiter.read().retain_recent();
     }
 }
@@ -1,13 +1,29 @@
-use crate::state::ProcessMetrics;
+use crate::output::{OutputProducer, OutputStream};
+use crate::state::{AppState, ProcessMetrics, ScopeSettings, TaskOutcome};
 use glob::glob;
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::sync::Arc;
 use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 use tokio::process::Command;
 use tokio::time::{timeout, Duration};
 
 const COMMAND_TIMEOUT_SECS: u64 = 120;
 
+/// How often the wait loop checks `state.is_request_cancelled` while a
+/// command is still running, so a server-initiated cancellation can be
+/// reported as such instead of just looking like the process vanished
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Outcome of waiting on a spawned command: either it finished on its own,
+/// it ran past `COMMAND_TIMEOUT_SECS`, or it was cancelled mid-flight
+enum CommandWait {
+    Finished(std::io::Result<std::process::ExitStatus>),
+    TimedOut,
+    Cancelled,
+}
+
 /// Result of a tool execution
 #[derive(Debug)]
 pub struct ToolResult {
@@ -16,11 +32,18 @@ pub struct ToolResult {
     pub error: Option<String>,
 }
 
-/// Execute a tool by name with the given input
+/// Execute a tool by name with the given input. `request_id` and `state`
+/// are only consulted by `run_command` (and `search_files`, which runs
+/// through it): to register the spawned process group so a
+/// `CancelToolRequest` can kill it mid-flight, to stream its output, to
+/// poll for cancellation and to record its outcome.
 pub async fn execute_tool(
     name: &str,
     input: &serde_json::Value,
     cwd: &str,
+    scope: &ScopeSettings,
+    request_id: &str,
+    state: &Arc<AppState>,
 ) -> ToolResult {
     // Ensure cwd exists
     let cwd_path = Path::new(cwd);
@@ -33,11 +56,11 @@ pub async fn execute_tool(
     }
 
     match name {
-        "read_file" => execute_read_file(input, cwd).await,
-        "write_file" => execute_write_file(input, cwd).await,
-        "list_files" => execute_list_files(input, cwd).await,
-        "run_command" => execute_run_command(input, cwd).await,
-        "search_files" => execute_search_files(input, cwd).await,
+        "read_file" => execute_read_file(input, cwd, scope).await,
+        "write_file" => execute_write_file(input, cwd, scope).await,
+        "list_files" => execute_list_files(input, cwd, scope).await,
+        "run_command" => execute_run_command(input, cwd, scope, request_id, state).await,
+        "search_files" => execute_search_files(input, cwd, scope, request_id, state).await,
         _ => ToolResult {
             result: format!("Unknown tool: {}", name),
             metrics: None,
@@ -46,7 +69,7 @@ pub async fn execute_tool(
     }
 }
 
-async fn execute_read_file(input: &serde_json::Value, cwd: &str) -> ToolResult {
+async fn execute_read_file(input: &serde_json::Value, cwd: &str, scope: &ScopeSettings) -> ToolResult {
     let path = match input.get("path").and_then(|p| p.as_str()) {
         Some(p) => p,
         None => {
@@ -58,7 +81,16 @@ async fn execute_read_file(input: &serde_json::Value, cwd: &str) -> ToolResult {
         }
     };
 
-    let full_path = resolve_path(cwd, path);
+    let full_path = match resolve_path(cwd, path, scope) {
+        Ok(p) => p,
+        Err(e) => {
+            return ToolResult {
+                result: format!("Error: {}", e),
+                metrics: None,
+                error: Some(e),
+            }
+        }
+    };
 
     if !full_path.exists() {
         return ToolResult {
@@ -82,7 +114,7 @@ async fn execute_read_file(input: &serde_json::Value, cwd: &str) -> ToolResult {
     }
 }
 
-async fn execute_write_file(input: &serde_json::Value, cwd: &str) -> ToolResult {
+async fn execute_write_file(input: &serde_json::Value, cwd: &str, scope: &ScopeSettings) -> ToolResult {
     let path = match input.get("path").and_then(|p| p.as_str()) {
         Some(p) => p,
         None => {
@@ -105,7 +137,16 @@ async fn execute_write_file(input: &serde_json::Value, cwd: &str) -> ToolResult
         }
     };
 
-    let full_path = resolve_path(cwd, path);
+    let full_path = match resolve_path(cwd, path, scope) {
+        Ok(p) => p,
+        Err(e) => {
+            return ToolResult {
+                result: format!("Error: {}", e),
+                metrics: None,
+                error: Some(e),
+            }
+        }
+    };
 
     // Create parent directories if needed
     if let Some(parent) = full_path.parent() {
@@ -134,7 +175,7 @@ async fn execute_write_file(input: &serde_json::Value, cwd: &str) -> ToolResult
     }
 }
 
-async fn execute_list_files(input: &serde_json::Value, cwd: &str) -> ToolResult {
+async fn execute_list_files(input: &serde_json::Value, cwd: &str, scope: &ScopeSettings) -> ToolResult {
     let path = input
         .get("path")
         .and_then(|p| p.as_str())
@@ -145,7 +186,16 @@ async fn execute_list_files(input: &serde_json::Value, cwd: &str) -> ToolResult
         .and_then(|p| p.as_str())
         .unwrap_or("*");
 
-    let full_path = resolve_path(cwd, path);
+    let full_path = match resolve_path(cwd, path, scope) {
+        Ok(p) => p,
+        Err(e) => {
+            return ToolResult {
+                result: format!("Error: {}", e),
+                metrics: None,
+                error: Some(e),
+            }
+        }
+    };
 
     if !full_path.exists() {
         return ToolResult {
@@ -187,7 +237,13 @@ async fn execute_list_files(input: &serde_json::Value, cwd: &str) -> ToolResult
     }
 }
 
-async fn execute_run_command(input: &serde_json::Value, cwd: &str) -> ToolResult {
+async fn execute_run_command(
+    input: &serde_json::Value,
+    cwd: &str,
+    scope: &ScopeSettings,
+    request_id: &str,
+    state: &Arc<AppState>,
+) -> ToolResult {
     let command = match input.get("command").and_then(|c| c.as_str()) {
         Some(c) => c,
         None => {
@@ -199,6 +255,14 @@ async fn execute_run_command(input: &serde_json::Value, cwd: &str) -> ToolResult
         }
     };
 
+    if !scope.is_command_allowed(command) {
+        return ToolResult {
+            result: "Error: command is not permitted by the configured scope".to_string(),
+            metrics: None,
+            error: Some("Command not allowed".to_string()),
+        };
+    }
+
     let start_time = Instant::now();
 
     #[cfg(target_os = "windows")]
@@ -207,16 +271,26 @@ async fn execute_run_command(input: &serde_json::Value, cwd: &str) -> ToolResult
     #[cfg(not(target_os = "windows"))]
     let (shell, shell_args) = ("/bin/bash", vec!["-c", command]);
 
-    let child = Command::new(shell)
-        .args(&shell_args)
+    let mut cmd = Command::new(shell);
+    cmd.args(&shell_args)
         .current_dir(cwd)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .spawn();
+        // Kill the child if this future is dropped/aborted (e.g. on
+        // cancellation) instead of leaking it as an orphan
+        .kill_on_drop(true);
+
+    // Put the command in its own process group so a cancellation can kill
+    // the whole tree it spawns, not just the shell itself
+    #[cfg(unix)]
+    cmd.process_group(0);
 
-    let child = match child {
+    let child = cmd.spawn();
+
+    let mut child = match child {
         Ok(c) => c,
         Err(e) => {
+            state.record_task_outcome(TaskOutcome::SpawnError);
             return ToolResult {
                 result: format!("Error spawning command: {}", e),
                 metrics: None,
@@ -225,70 +299,171 @@ async fn execute_run_command(input: &serde_json::Value, cwd: &str) -> ToolResult
         }
     };
 
-    // Wait for command with timeout
-    let result = timeout(
-        Duration::from_secs(COMMAND_TIMEOUT_SECS),
-        child.wait_with_output(),
-    )
-    .await;
+    let sampler = child.id().map(crate::metrics::ProcessSampler::spawn);
+    if let Some(pgid) = child.id() {
+        state.register_process(request_id.to_string(), pgid);
+    }
+
+    // Stream stdout/stderr as they're produced rather than buffering the
+    // whole thing until the command exits, so a subscriber can show live
+    // output and a large result doesn't balloon memory
+    let producer = state.output_producer(request_id);
+    let stdout_task = tokio::spawn(collect_and_stream(
+        child.stdout.take(),
+        OutputStream::Stdout,
+        producer.clone(),
+    ));
+    let stderr_task = tokio::spawn(collect_and_stream(
+        child.stderr.take(),
+        OutputStream::Stderr,
+        producer,
+    ));
+
+    // Wait for the command, polling for a server-initiated cancellation in
+    // between so it's reported distinctly from a timeout
+    let wait = loop {
+        if state.is_request_cancelled(request_id) {
+            let _ = child.start_kill();
+            let _ = child.wait().await;
+            break CommandWait::Cancelled;
+        }
+
+        let elapsed = start_time.elapsed();
+        let total_timeout = Duration::from_secs(COMMAND_TIMEOUT_SECS);
+        if elapsed >= total_timeout {
+            let _ = child.start_kill();
+            let _ = child.wait().await;
+            break CommandWait::TimedOut;
+        }
+
+        let tick = (total_timeout - elapsed).min(CANCEL_POLL_INTERVAL);
+        match timeout(tick, child.wait()).await {
+            Ok(status) => break CommandWait::Finished(status),
+            Err(_) => continue,
+        }
+    };
+
+    let stdout_text = stdout_task.await.unwrap_or_default();
+    let stderr_text = stderr_task.await.unwrap_or_default();
 
+    state.remove_process(request_id);
     let duration_ms = start_time.elapsed().as_millis() as u64;
+    let metrics = sampler.map(|s| s.finish(duration_ms));
+
+    match wait {
+        CommandWait::Finished(Ok(status)) => {
+            // `.code()` is `None` both for a clean signal kill and in a few
+            // other platform-specific edge cases; `.signal()` is the only
+            // reliable way to tell "the process was killed by a signal"
+            // (OOM-kill, segfault, etc.) from an ordinary non-zero exit, so
+            // those don't get silently merged into `non_zero_exit` stats.
+            #[cfg(unix)]
+            let signal = {
+                use std::os::unix::process::ExitStatusExt;
+                status.signal()
+            };
+            #[cfg(not(unix))]
+            let signal: Option<i32> = None;
 
-    match result {
-        Ok(Ok(output)) => {
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            let stderr = String::from_utf8_lossy(&output.stderr);
+            let outcome = if status.success() {
+                TaskOutcome::Success
+            } else if signal.is_some() {
+                TaskOutcome::Killed
+            } else {
+                TaskOutcome::NonZeroExit {
+                    code: status.code().unwrap_or(-1),
+                }
+            };
+            state.record_task_outcome(outcome);
 
-            let result_text = if !stdout.is_empty() {
-                stdout.to_string()
-            } else if !stderr.is_empty() {
-                stderr.to_string()
+            let result_text = if !stdout_text.is_empty() {
+                stdout_text
+            } else if !stderr_text.is_empty() {
+                stderr_text
             } else {
                 "(command completed with no output)".to_string()
             };
 
             ToolResult {
                 result: result_text,
-                metrics: Some(ProcessMetrics {
-                    cpu_percent: 0.0, // Note: per-process CPU tracking is complex in Rust
-                    memory_mb: 0.0,
-                    duration_ms,
-                }),
-                error: if output.status.success() {
+                metrics,
+                error: if status.success() {
                     None
+                } else if let Some(signal) = signal {
+                    Some(format!("Killed by signal: {}", signal))
                 } else {
-                    Some(format!("Exit code: {}", output.status.code().unwrap_or(-1)))
+                    Some(format!("Exit code: {}", status.code().unwrap_or(-1)))
                 },
             }
         }
-        Ok(Err(e)) => ToolResult {
-            result: format!("Error executing command: {}", e),
-            metrics: Some(ProcessMetrics {
-                cpu_percent: 0.0,
-                memory_mb: 0.0,
-                duration_ms,
-            }),
-            error: Some(e.to_string()),
-        },
-        Err(_) => {
-            // Timeout - process is dropped which should kill it
+        CommandWait::Finished(Err(e)) => {
+            // A failure to even reap the process once it's exited is as
+            // close to "never ran successfully" as the outcome enum gets
+            state.record_task_outcome(TaskOutcome::SpawnError);
+            ToolResult {
+                result: format!("Error executing command: {}", e),
+                metrics,
+                error: Some(e.to_string()),
+            }
+        }
+        CommandWait::TimedOut => {
+            state.record_task_outcome(TaskOutcome::Timeout);
             ToolResult {
                 result: format!(
                     "Command timed out after {} seconds",
                     COMMAND_TIMEOUT_SECS
                 ),
-                metrics: Some(ProcessMetrics {
-                    cpu_percent: 0.0,
-                    memory_mb: 0.0,
-                    duration_ms,
-                }),
+                metrics,
                 error: Some("Command timed out".to_string()),
             }
         }
+        CommandWait::Cancelled => {
+            state.record_task_outcome(TaskOutcome::Cancelled);
+            ToolResult {
+                result: "Command cancelled".to_string(),
+                metrics,
+                error: Some("cancelled".to_string()),
+            }
+        }
+    }
+}
+
+/// Read `pipe` line by line until it closes, pushing each line through
+/// `producer` (if the command's output is being streamed) while also
+/// collecting it into the full text returned for the final aggregated result
+async fn collect_and_stream<R>(
+    pipe: Option<R>,
+    stream: OutputStream,
+    producer: Option<OutputProducer>,
+) -> String
+where
+    R: AsyncRead + Unpin,
+{
+    let Some(pipe) = pipe else {
+        return String::new();
+    };
+
+    let mut lines = BufReader::new(pipe).lines();
+    let mut collected = String::new();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        if let Some(producer) = &producer {
+            producer.push(stream, line.clone().into_bytes()).await;
+        }
+        collected.push_str(&line);
+        collected.push('\n');
     }
+
+    collected
 }
 
-async fn execute_search_files(input: &serde_json::Value, cwd: &str) -> ToolResult {
+async fn execute_search_files(
+    input: &serde_json::Value,
+    cwd: &str,
+    scope: &ScopeSettings,
+    request_id: &str,
+    state: &Arc<AppState>,
+) -> ToolResult {
     let path = input
         .get("path")
         .and_then(|p| p.as_str())
@@ -305,7 +480,16 @@ async fn execute_search_files(input: &serde_json::Value, cwd: &str) -> ToolResul
         }
     };
 
-    let full_path = resolve_path(cwd, path);
+    let full_path = match resolve_path(cwd, path, scope) {
+        Ok(p) => p,
+        Err(e) => {
+            return ToolResult {
+                result: format!("Error: {}", e),
+                metrics: None,
+                error: Some(e),
+            }
+        }
+    };
 
     #[cfg(target_os = "windows")]
     let cmd = format!(
@@ -323,7 +507,7 @@ async fn execute_search_files(input: &serde_json::Value, cwd: &str) -> ToolResul
 
     // Reuse run_command
     let input = serde_json::json!({ "command": cmd });
-    let result = execute_run_command(&input, cwd).await;
+    let result = execute_run_command(&input, cwd, scope, request_id, state).await;
 
     if result.result.trim().is_empty() {
         ToolResult {
@@ -336,13 +520,124 @@ async fn execute_search_files(input: &serde_json::Value, cwd: &str) -> ToolResul
     }
 }
 
-/// Resolve a path relative to the working directory
-fn resolve_path(cwd: &str, path: &str) -> PathBuf {
+/// Kill the process group spawned for a `run_command` invocation, so
+/// cancellation takes down the whole tree rather than leaving grandchildren
+/// (e.g. a shell's own subprocesses) running.
+pub fn kill_process_group(pgid: u32) {
+    #[cfg(unix)]
+    {
+        let _ = std::process::Command::new("kill")
+            .args(["-9", &format!("-{}", pgid)])
+            .status();
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        let _ = std::process::Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &pgid.to_string()])
+            .status();
+    }
+}
+
+/// Canonicalize as much of `path` as exists on disk, then re-append the
+/// non-existent tail. This lets scope-checking work for paths that don't
+/// exist yet (e.g. a file `write_file` is about to create) while still
+/// resolving `..` and symlinks in the part that does exist.
+fn canonicalize_best_effort(path: &Path) -> PathBuf {
+    let mut base = path.to_path_buf();
+    let mut tail: Vec<std::ffi::OsString> = Vec::new();
+
+    while !base.exists() {
+        match base.file_name() {
+            Some(name) => tail.push(name.to_os_string()),
+            None => break,
+        }
+        if !base.pop() {
+            break;
+        }
+    }
+
+    let mut resolved = base.canonicalize().unwrap_or(base);
+    for component in tail.into_iter().rev() {
+        resolved.push(component);
+    }
+    resolved
+}
+
+/// Validate a `ToolRequest`'s caller-supplied `cwd` against the agent's own
+/// scope before it's ever used as the anchor `resolve_path` checks against.
+/// `cwd` comes from the remote server on every request; without this check,
+/// a malicious server could send `cwd: "/"` and the default scope (no
+/// `allowed_roots` configured, so the anchor is just `cwd` itself) would let
+/// `read_file`/`write_file`/`list_files` reach anywhere on disk. Falls back
+/// to `working_dir` if the requested `cwd` escapes the allowed roots.
+pub fn pin_cwd(requested_cwd: Option<&str>, working_dir: &str, scope: &ScopeSettings) -> String {
+    let Some(requested) = requested_cwd else {
+        return working_dir.to_string();
+    };
+
+    let resolved = canonicalize_best_effort(Path::new(requested));
+
+    let roots: Vec<PathBuf> = if scope.allowed_roots.is_empty() {
+        vec![canonicalize_best_effort(Path::new(working_dir))]
+    } else {
+        scope
+            .allowed_roots
+            .iter()
+            .map(|root| canonicalize_best_effort(Path::new(root)))
+            .collect()
+    };
+
+    // A `..` still left in `resolved` sits under a part of the path that
+    // doesn't exist, so it was never resolved and can still climb out
+    let unresolved_parent = resolved
+        .components()
+        .any(|c| matches!(c, std::path::Component::ParentDir));
+
+    if !unresolved_parent && roots.iter().any(|root| resolved.starts_with(root)) {
+        requested.to_string()
+    } else {
+        working_dir.to_string()
+    }
+}
+
+/// Resolve a path relative to the working directory, rejecting any result
+/// that escapes the roots allowed by `scope`
+fn resolve_path(cwd: &str, path: &str, scope: &ScopeSettings) -> Result<PathBuf, String> {
     let path = Path::new(path);
-    if path.is_absolute() {
+    let full_path = if path.is_absolute() {
         path.to_path_buf()
     } else {
         Path::new(cwd).join(path)
+    };
+
+    let resolved = canonicalize_best_effort(&full_path);
+
+    let roots: Vec<PathBuf> = if scope.allowed_roots.is_empty() {
+        vec![canonicalize_best_effort(Path::new(cwd))]
+    } else {
+        scope
+            .allowed_roots
+            .iter()
+            .map(|root| canonicalize_best_effort(Path::new(root)))
+            .collect()
+    };
+
+    // A `..` still left in `resolved` sits under a part of the path that
+    // doesn't exist, so it was never resolved and can still climb out
+    let unresolved_parent = resolved
+        .components()
+        .any(|c| matches!(c, std::path::Component::ParentDir));
+
+    if !unresolved_parent && roots.iter().any(|root| resolved.starts_with(root)) {
+        // Use the canonicalized path, not `full_path`, for the actual I/O -
+        // otherwise a symlink created between this check and the caller's
+        // filesystem call (e.g. by a preceding `run_command`) would never
+        // get re-validated, since the non-canonical join doesn't know about
+        // it either way.
+        Ok(resolved)
+    } else {
+        Err(format!("Path escapes allowed scope: {}", full_path.display()))
     }
 }
 
@@ -352,13 +647,49 @@ mod tests {
 
     #[test]
     fn test_resolve_path_relative() {
-        let result = resolve_path("/home/user", "file.txt");
+        let scope = ScopeSettings::default();
+        let result = resolve_path("/home/user", "file.txt", &scope).unwrap();
         assert_eq!(result, PathBuf::from("/home/user/file.txt"));
     }
 
     #[test]
     fn test_resolve_path_absolute() {
-        let result = resolve_path("/home/user", "/etc/config");
+        let scope = ScopeSettings {
+            allowed_roots: vec!["/etc".to_string()],
+            allowed_commands: vec![],
+        };
+        let result = resolve_path("/home/user", "/etc/config", &scope).unwrap();
         assert_eq!(result, PathBuf::from("/etc/config"));
     }
+
+    #[test]
+    fn test_resolve_path_rejects_escape() {
+        let scope = ScopeSettings::default();
+        let result = resolve_path("/home/user", "../../etc/passwd", &scope);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_pin_cwd_rejects_cwd_outside_working_dir() {
+        let scope = ScopeSettings::default();
+        let result = pin_cwd(Some("/"), "/home/user/project", &scope);
+        assert_eq!(result, "/home/user/project");
+    }
+
+    #[test]
+    fn test_pin_cwd_allows_cwd_inside_configured_root() {
+        let scope = ScopeSettings {
+            allowed_roots: vec!["/srv".to_string()],
+            allowed_commands: vec![],
+        };
+        let result = pin_cwd(Some("/srv/app"), "/home/user", &scope);
+        assert_eq!(result, "/srv/app");
+    }
+
+    #[test]
+    fn test_pin_cwd_defaults_when_absent() {
+        let scope = ScopeSettings::default();
+        let result = pin_cwd(None, "/home/user/project", &scope);
+        assert_eq!(result, "/home/user/project");
+    }
 }
@@ -1,9 +1,13 @@
+use crate::identity;
 use crate::metrics::{collect_system_metrics, get_system_info, is_system_overloaded};
-use crate::state::{AppState, ConnectionStatus, QueuedTask};
+use crate::queue_store;
+use crate::state::{AppState, CancelOutcome, ConnectionStatus, QueuedTask, ReconnectStrategy};
 use crate::tools::execute_tool;
 use futures_util::{SinkExt, StreamExt};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tauri::{AppHandle, Emitter};
 use tokio::net::TcpStream;
@@ -15,13 +19,65 @@ use tokio_tungstenite::{
 
 const VERSION: &str = "0.1.0";
 const STATUS_INTERVAL_MS: u64 = 5000;
-const RECONNECT_DELAY_MS: u64 = 5000;
 const PING_INTERVAL_MS: u64 = 30000;
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+/// Fallback poll interval for queue workers, in case system load recovers
+/// on its own without an `enqueue_task`/slot-free notification to wake them
+const QUEUE_DRAIN_MAX_MS: u64 = 30_000;
+
+/// A failed queued `run_command` gets this many attempts in all (its first
+/// run plus retries) before it is given up on and moved to the dead-letter log
+const MAX_TASK_ATTEMPTS: u32 = 5;
+const RETRY_BASE_DELAY_MS: i64 = 2_000;
+const RETRY_MAX_DELAY_MS: i64 = 10 * 60 * 1000;
+
+/// Capped exponential backoff for a queued task's next retry attempt
+fn retry_backoff(attempts: u32) -> chrono::Duration {
+    let delay_ms = RETRY_BASE_DELAY_MS.saturating_mul(1i64 << attempts.min(20));
+    chrono::Duration::milliseconds(delay_ms.min(RETRY_MAX_DELAY_MS))
+}
+
+/// Compute the delay before the next reconnect attempt, applying full jitter
+/// in the `Exponential` case so many agents dropping at once don't all
+/// retry in lockstep.
+fn compute_reconnect_delay(strategy: ReconnectStrategy, attempt: u32, base_delay_ms: u64, max_delay_ms: u64) -> Duration {
+    let delay_ms = match strategy {
+        // No jitter here: the doc comment on `ReconnectStrategy::Constant`
+        // promises exactly `reconnect_base_delay_ms` between attempts, and
+        // jittering it would make it indistinguishable from `Exponential`
+        // stuck at `attempt=0` forever.
+        ReconnectStrategy::Constant => return Duration::from_millis(base_delay_ms.min(max_delay_ms)),
+        ReconnectStrategy::Exponential => {
+            let scaled = base_delay_ms.saturating_mul(1u64 << attempt.min(32));
+            scaled.min(max_delay_ms)
+        }
+    };
+
+    let jittered_ms = if delay_ms == 0 {
+        0
+    } else {
+        rand::thread_rng().gen_range(0..=delay_ms)
+    };
+
+    Duration::from_millis(jittered_ms)
+}
 
 /// Messages sent to the WebSocket server
 #[derive(Debug, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum OutgoingMessage {
+    AgentHello {
+        #[serde(rename = "publicKey")]
+        public_key: String,
+        /// Nonce the agent challenges the server with in turn, so a server
+        /// presenting a trusted key can prove possession of it rather than
+        /// the handshake only authenticating the agent to the server
+        #[serde(rename = "agentNonce")]
+        agent_nonce: String,
+    },
+    ChallengeResponse {
+        signature: String,
+    },
     AgentRegister {
         #[serde(rename = "workingDir")]
         working_dir: String,
@@ -29,6 +85,8 @@ enum OutgoingMessage {
         version: String,
         #[serde(rename = "systemInfo")]
         system_info: crate::metrics::SystemInfo,
+        #[serde(rename = "recoveredTasks")]
+        recovered_tasks: usize,
     },
     ToolResponse {
         #[serde(rename = "requestId")]
@@ -44,6 +102,9 @@ enum OutgoingMessage {
         queued: Option<bool>,
         #[serde(rename = "queuePosition", skip_serializing_if = "Option::is_none")]
         queue_position: Option<usize>,
+        redelivered: bool,
+        #[serde(rename = "retryAfterMs", skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
     },
     ToolQueued {
         #[serde(rename = "requestId")]
@@ -57,12 +118,28 @@ enum OutgoingMessage {
     AgentStatusUpdate {
         metrics: crate::state::SystemMetrics,
     },
+    ToolOutputChunk {
+        #[serde(rename = "requestId")]
+        request_id: String,
+        stream: crate::output::OutputStream,
+        seq: u64,
+        data: String,
+    },
 }
 
 /// Messages received from the WebSocket server
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum IncomingMessage {
+    Challenge {
+        nonce: String,
+        /// Signature over this connection's `agentNonce`, signed with the
+        /// server's private key. Only present when the server supports
+        /// pinning; absent (or unverifiable) is treated as "untrusted" once
+        /// `trusted_server_public_key` is configured.
+        #[serde(rename = "serverSignature", default)]
+        server_signature: Option<String>,
+    },
     AgentRegistered {
         #[serde(rename = "agentId")]
         agent_id: String,
@@ -75,6 +152,14 @@ enum IncomingMessage {
         tool: String,
         input: serde_json::Value,
         cwd: Option<String>,
+        /// Higher values jump the task to the front of its queue shard
+        /// instead of the back when it has to wait
+        #[serde(default)]
+        priority: i32,
+    },
+    CancelToolRequest {
+        #[serde(rename = "requestId")]
+        request_id: String,
     },
     GetStatus,
 }
@@ -88,6 +173,8 @@ pub enum WsCommand {
 
 /// Start the WebSocket connection manager
 pub async fn start_connection(app_handle: AppHandle, state: Arc<AppState>) {
+    let mut attempt: u32 = 0;
+
     loop {
         let settings = state.settings.read().clone();
         let server_url = settings.server_url.clone();
@@ -105,8 +192,13 @@ pub async fn start_connection(app_handle: AppHandle, state: Arc<AppState>) {
                 state.log_success("Connected to server");
                 emit_status_update(&app_handle, &state);
 
-                // Run the connection handler
-                handle_connection(
+                // Run the connection handler. The WS upgrade succeeding
+                // doesn't mean the connection is actually usable - a server
+                // that completes the upgrade but then fails the auth
+                // handshake every time would otherwise pin `attempt` at 0
+                // forever, defeating the backoff. Only reset it once the
+                // connection reached a registered, working state.
+                let registered = handle_connection(
                     ws_stream,
                     app_handle.clone(),
                     state.clone(),
@@ -115,6 +207,10 @@ pub async fn start_connection(app_handle: AppHandle, state: Arc<AppState>) {
                 )
                 .await;
 
+                if registered {
+                    attempt = 0;
+                }
+
                 // Connection closed
                 *state.is_connected.write() = false;
                 state.set_status(ConnectionStatus::Disconnected);
@@ -133,11 +229,17 @@ pub async fn start_connection(app_handle: AppHandle, state: Arc<AppState>) {
             break;
         }
 
-        state.log_info(format!(
-            "Reconnecting in {} seconds...",
-            RECONNECT_DELAY_MS / 1000
-        ));
-        tokio::time::sleep(Duration::from_millis(RECONNECT_DELAY_MS)).await;
+        let settings = state.settings.read().clone();
+        let delay = compute_reconnect_delay(
+            settings.reconnect_strategy,
+            attempt,
+            settings.reconnect_base_delay_ms,
+            settings.reconnect_max_delay_ms,
+        );
+        attempt = attempt.saturating_add(1);
+
+        state.log_info(format!("Reconnecting in {}ms...", delay.as_millis()));
+        tokio::time::sleep(delay).await;
     }
 }
 
@@ -147,12 +249,94 @@ async fn handle_connection(
     state: Arc<AppState>,
     working_dir: String,
     max_concurrent: u32,
-) {
+) -> bool {
     let (mut write, mut read) = ws_stream.split();
 
     // Create channel for sending messages to the WebSocket
     let (tx, mut rx) = mpsc::channel::<WsCommand>(100);
 
+    // Prove our identity to the server before it will trust us: send our
+    // public key, sign whatever nonce it challenges us with, and only then
+    // proceed to registration. We also challenge the server back with our
+    // own nonce, so that once `trusted_server_public_key` is configured the
+    // handshake authenticates both directions instead of only the agent -
+    // without it, any server can still complete the handshake and drive
+    // tool execution exactly as before this setting existed.
+    let mut seed = state.settings.read().signing_key_seed.clone();
+    let signing_key = identity::load_or_create_signing_key(&mut seed);
+    if state.settings.read().signing_key_seed != seed {
+        state.settings.write().signing_key_seed = seed;
+    }
+
+    let trusted_server_key = state.settings.read().trusted_server_public_key.clone();
+    let agent_nonce = identity::generate_nonce_b64();
+
+    let hello_msg = OutgoingMessage::AgentHello {
+        public_key: identity::public_key_b64(&signing_key),
+        agent_nonce: agent_nonce.clone(),
+    };
+    if let Ok(msg) = serde_json::to_string(&hello_msg) {
+        let _ = write.send(Message::Text(msg.into())).await;
+    }
+
+    let challenge = loop {
+        match read.next().await {
+            Some(Ok(Message::Text(text))) => match serde_json::from_str::<IncomingMessage>(&text) {
+                Ok(IncomingMessage::Challenge { nonce, server_signature }) => {
+                    break Some((nonce, server_signature))
+                }
+                Ok(IncomingMessage::AgentRegistered { .. }) => break None,
+                _ => continue,
+            },
+            Some(Ok(Message::Close(_))) | None => break None,
+            Some(Err(_)) => break None,
+            _ => continue,
+        }
+    };
+
+    let (challenge_nonce, server_signature) = match challenge {
+        Some(challenge) => challenge,
+        None => {
+            state.log_error("Server did not issue an auth challenge; rejecting connection");
+            state.set_status(ConnectionStatus::Disconnected);
+            emit_status_update(&app_handle, &state);
+            let _ = write.close().await;
+            return false;
+        }
+    };
+
+    if let Some(trusted_key) = trusted_server_key {
+        let verified = server_signature
+            .as_deref()
+            .is_some_and(|sig| identity::verify_signature_b64(&trusted_key, &agent_nonce, sig));
+
+        if !verified {
+            state.log_error(
+                "Server failed to prove possession of the trusted server key; rejecting connection",
+            );
+            state.set_status(ConnectionStatus::Disconnected);
+            emit_status_update(&app_handle, &state);
+            let _ = write.close().await;
+            return false;
+        }
+    }
+
+    match identity::sign_nonce_b64(&signing_key, &challenge_nonce) {
+        Ok(signature) => {
+            let response = OutgoingMessage::ChallengeResponse { signature };
+            if let Ok(msg) = serde_json::to_string(&response) {
+                let _ = write.send(Message::Text(msg.into())).await;
+            }
+        }
+        Err(e) => {
+            state.log_error(format!("Failed to sign auth challenge: {}", e));
+            state.set_status(ConnectionStatus::Disconnected);
+            emit_status_update(&app_handle, &state);
+            let _ = write.close().await;
+            return false;
+        }
+    }
+
     // Send registration message
     let system_info = get_system_info();
     let register_msg = OutgoingMessage::AgentRegister {
@@ -160,12 +344,23 @@ async fn handle_connection(
         platform: std::env::consts::OS.to_string(),
         version: VERSION.to_string(),
         system_info,
+        recovered_tasks: state.take_recovered_task_count(),
     };
 
     if let Ok(msg) = serde_json::to_string(&register_msg) {
         let _ = write.send(Message::Text(msg.into())).await;
     }
 
+    // Flipped once the server actually confirms registration
+    // (`AgentRegistered`), so the caller can tell "reached a working state"
+    // from "the WS upgrade merely succeeded" before resetting its backoff.
+    let registered = Arc::new(AtomicBool::new(false));
+
+    // Tracks when we last heard a pong back from the server; the watchdog
+    // task below uses this to detect a half-open connection.
+    let last_pong = Arc::new(parking_lot::Mutex::new(std::time::Instant::now()));
+    let missed_heartbeat_limit = state.settings.read().missed_heartbeat_limit;
+
     // Clone state for tasks
     let state_for_status = state.clone();
     let tx_for_status = tx.clone();
@@ -177,6 +372,7 @@ async fn handle_connection(
             status_interval.tick().await;
             let metrics = collect_system_metrics(&state_for_status);
             state_for_status.update_metrics(metrics.clone());
+            state_for_status.expire_idle_rate_limits();
 
             let msg = OutgoingMessage::AgentStatusUpdate { metrics };
             if let Ok(json) = serde_json::to_string(&msg) {
@@ -200,6 +396,53 @@ async fn handle_connection(
         }
     });
 
+    // Queue workers - one persistent worker per shard, each pulling from its
+    // own shard first and stealing from the others once it runs dry. They
+    // wake as soon as `enqueue_task` notifies them or a slot frees up, with a
+    // periodic fallback poll so system load recovering on its own (e.g.
+    // another process on the machine quieting down) still gets noticed.
+    let queue_worker_tasks: Vec<_> = (0..max_concurrent as usize)
+        .map(|home| {
+            tokio::spawn(run_queue_worker(
+                home,
+                tx.clone(),
+                state.clone(),
+                app_handle.clone(),
+                state.task_runner.shutdown_signal(),
+            ))
+        })
+        .collect();
+
+    // Watchdog task - closes the connection if the server stops ponging.
+    // A half-open TCP connection (server vanished without a FIN/RST) leaves
+    // `read.next()` parked forever, so sending `WsCommand::Close` to the
+    // write task alone would never actually unblock the read loop below;
+    // `stale_signal` is what directly races it out of that stuck await.
+    let last_pong_for_watchdog = last_pong.clone();
+    let tx_for_watchdog = tx.clone();
+    let state_for_watchdog = state.clone();
+    let app_for_watchdog = app_handle.clone();
+    let stale_signal = Arc::new(tokio::sync::Notify::new());
+    let stale_signal_for_watchdog = stale_signal.clone();
+    let watchdog_task = tokio::spawn(async move {
+        let mut watchdog_interval = interval(Duration::from_millis(PING_INTERVAL_MS));
+        let stale_after = Duration::from_millis(PING_INTERVAL_MS * missed_heartbeat_limit.max(1) as u64);
+        loop {
+            watchdog_interval.tick().await;
+            let elapsed = last_pong_for_watchdog.lock().elapsed();
+            if elapsed > stale_after {
+                state_for_watchdog.log_warning(format!(
+                    "No pong received in {}ms, closing stale connection",
+                    elapsed.as_millis()
+                ));
+                emit_status_update(&app_for_watchdog, &state_for_watchdog);
+                let _ = tx_for_watchdog.send(WsCommand::Close).await;
+                stale_signal_for_watchdog.notify_one();
+                break;
+            }
+        }
+    });
+
     // Write task - sends messages from channel to WebSocket
     let write_task = tokio::spawn(async move {
         while let Some(cmd) = rx.recv().await {
@@ -227,7 +470,16 @@ async fn handle_connection(
     let state_for_read = state.clone();
     let app_for_read = app_handle.clone();
 
-    while let Some(msg_result) = read.next().await {
+    loop {
+        let msg_result = tokio::select! {
+            msg = read.next() => msg,
+            _ = stale_signal.notified() => None,
+        };
+
+        let Some(msg_result) = msg_result else {
+            break;
+        };
+
         match msg_result {
             Ok(Message::Text(text)) => {
                 if let Ok(msg) = serde_json::from_str::<IncomingMessage>(&text) {
@@ -238,10 +490,14 @@ async fn handle_connection(
                         app_for_read.clone(),
                         &working_dir,
                         max_concurrent,
+                        &registered,
                     )
                     .await;
                 }
             }
+            Ok(Message::Pong(_)) => {
+                *last_pong.lock() = std::time::Instant::now();
+            }
             Ok(Message::Close(_)) => {
                 break;
             }
@@ -256,7 +512,23 @@ async fn handle_connection(
     let _ = tx.send(WsCommand::Close).await;
     status_task.abort();
     ping_task.abort();
+    watchdog_task.abort();
     write_task.abort();
+
+    // Flips the shutdown signal the queue workers watch, so they stop
+    // picking up new work as soon as they notice, then lets in-flight tool
+    // executions finish (or aborts them after a grace period) so nothing
+    // keeps running unsupervised across a reconnect.
+    state.task_runner.shutdown(SHUTDOWN_GRACE).await;
+
+    // The queue workers aren't part of the runner's tracked set - they only
+    // feed it - so they should have already exited on their own by now via
+    // the shutdown signal; abort defensively in case one is stuck.
+    for worker in &queue_worker_tasks {
+        worker.abort();
+    }
+
+    registered.load(Ordering::Relaxed)
 }
 
 async fn handle_message(
@@ -266,10 +538,12 @@ async fn handle_message(
     app_handle: AppHandle,
     working_dir: &str,
     max_concurrent: u32,
+    registered: &AtomicBool,
 ) {
     match msg {
         IncomingMessage::AgentRegistered { agent_id } => {
             *state.agent_id.write() = Some(agent_id.clone());
+            registered.store(true, Ordering::Relaxed);
             state.log_success(format!("Registered as agent: {}", agent_id));
 
             let info = get_system_info();
@@ -285,23 +559,66 @@ async fn handle_message(
             tool,
             input,
             cwd,
+            priority,
         } => {
+            if let Err(retry_after) = state.check_rate_limit(&session_id) {
+                state.log_warning(format!(
+                    "Rate limited session {} ({}ms until retry)",
+                    session_id,
+                    retry_after.as_millis()
+                ));
+
+                let response = OutgoingMessage::ToolResponse {
+                    request_id,
+                    session_id,
+                    result: String::new(),
+                    error: Some("rate_limited".to_string()),
+                    metrics: None,
+                    queued: None,
+                    queue_position: None,
+                    redelivered: false,
+                    retry_after_ms: Some(retry_after.as_millis() as u64),
+                };
+                if let Ok(json) = serde_json::to_string(&response) {
+                    let _ = tx.send(WsCommand::Send(json)).await;
+                }
+
+                emit_status_update(&app_handle, &state);
+                return;
+            }
+
+            let settings = state.settings.read().clone();
             let metrics = collect_system_metrics(&state);
             let active = state.get_active_commands();
-            let should_queue =
-                active >= max_concurrent || (tool == "run_command" && is_system_overloaded(&metrics));
+            let overloaded = is_system_overloaded(&metrics, &settings);
+
+            // Every `run_command` now flows through the queue and is picked
+            // up by a persistent queue worker, so its `active_commands` slot
+            // is reserved in exactly one place (the workers, at reservation
+            // time in `run_queue_worker`) instead of racing an
+            // immediate-dispatch path against them. Other tools were never
+            // subject to the concurrency cap and still run inline, counted
+            // in `active_commands` by `execute_task` while they run.
+            let should_queue = tool == "run_command";
+
+            if should_queue {
+                state.register_request(request_id.clone(), session_id.clone());
 
-            if should_queue && tool == "run_command" {
                 // Queue the task
                 let task = QueuedTask {
                     request_id: request_id.clone(),
                     session_id: session_id.clone(),
                     tool: tool.clone(),
                     input,
-                    cwd: cwd.unwrap_or_else(|| working_dir.to_string()),
+                    cwd: crate::tools::pin_cwd(cwd.as_deref(), working_dir, &settings.scope),
                     queued_at: chrono::Utc::now(),
+                    redelivered: false,
+                    attempts: 0,
+                    next_attempt_at: None,
+                    priority,
                 };
 
+                queue_store::persist_task(working_dir, &task);
                 let position = state.enqueue_task(task);
                 state.log_warning(format!(
                     "Queued: {} [position {}] (CPU: {}%, Mem: {}%)",
@@ -318,6 +635,17 @@ async fn handle_message(
                     )
                 };
 
+                if overloaded && active < max_concurrent {
+                    let _ = app_handle.emit(
+                        "task-throttled",
+                        json!({
+                            "requestId": request_id,
+                            "cpuPercent": metrics.cpu_percent,
+                            "memoryPercent": metrics.memory_percent,
+                        }),
+                    );
+                }
+
                 let queued_msg = OutgoingMessage::ToolQueued {
                     request_id,
                     session_id,
@@ -329,23 +657,65 @@ async fn handle_message(
                     let _ = tx.send(WsCommand::Send(json)).await;
                 }
             } else {
-                // Execute immediately
-                execute_task(
+                // Execute immediately, keeping an abort handle so the server
+                // can cancel it mid-flight
+                state.register_request(request_id.clone(), session_id.clone());
+                spawn_execute_task(
                     request_id,
                     session_id,
                     tool,
                     input,
-                    cwd.unwrap_or_else(|| working_dir.to_string()),
+                    crate::tools::pin_cwd(cwd.as_deref(), working_dir, &settings.scope),
                     tx.clone(),
                     state.clone(),
                     app_handle.clone(),
                     false,
-                )
-                .await;
+                    false,
+                    0,
+                    false,
+                );
             }
 
             emit_status_update(&app_handle, &state);
         }
+        IncomingMessage::CancelToolRequest { request_id } => {
+            let cancelled = match state.cancel_request(&request_id) {
+                Some(CancelOutcome::WasRunning(session_id)) => {
+                    state.decrement_active_commands();
+                    Some(session_id)
+                }
+                Some(CancelOutcome::WasQueued(task)) => {
+                    queue_store::remove_task(working_dir, &task.request_id);
+                    Some(task.session_id)
+                }
+                None => None,
+            };
+
+            if let Some(session_id) = cancelled {
+                state.log_warning(format!("Cancelled request {}", request_id));
+
+                let response = OutgoingMessage::ToolResponse {
+                    request_id,
+                    session_id,
+                    result: String::new(),
+                    error: Some("cancelled".to_string()),
+                    metrics: None,
+                    queued: None,
+                    queue_position: None,
+                    redelivered: false,
+                    retry_after_ms: None,
+                };
+                if let Ok(json) = serde_json::to_string(&response) {
+                    let _ = tx.send(WsCommand::Send(json)).await;
+                }
+
+                emit_status_update(&app_handle, &state);
+                // A cancelled run may have freed up an active-command slot;
+                // wake the queue workers so a queued task can claim it
+                // immediately instead of waiting for their fallback poll.
+                state.queue_notify.notify_one();
+            }
+        }
         IncomingMessage::GetStatus => {
             let metrics = collect_system_metrics(&state);
             let msg = OutgoingMessage::AgentStatusUpdate { metrics };
@@ -356,6 +726,75 @@ async fn handle_message(
     }
 }
 
+/// Relay chunks pushed to `subscriber` onward to the server as
+/// `ToolOutputChunk` messages until the producer side closes (the command
+/// finished) or the WebSocket write loop goes away
+fn spawn_output_forwarder(
+    mut subscriber: crate::output::OutputSubscriber,
+    tx: mpsc::Sender<WsCommand>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some(chunk) = subscriber.rx.recv().await {
+            let message = OutgoingMessage::ToolOutputChunk {
+                request_id: chunk.request_id,
+                stream: chunk.stream,
+                seq: chunk.seq,
+                data: String::from_utf8_lossy(&chunk.bytes).to_string(),
+            };
+            if let Ok(text) = serde_json::to_string(&message) {
+                if tx.send(WsCommand::Send(text)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    })
+}
+
+/// Spawn `execute_task` as its own task and register an abort handle for it
+/// so a `CancelToolRequest` can stop it mid-flight
+fn spawn_execute_task(
+    request_id: String,
+    session_id: String,
+    tool: String,
+    input: serde_json::Value,
+    cwd: String,
+    tx: mpsc::Sender<WsCommand>,
+    state: Arc<AppState>,
+    app_handle: AppHandle,
+    was_queued: bool,
+    redelivered: bool,
+    attempts: u32,
+    slot_reserved: bool,
+) {
+    let request_id_for_registry = request_id.clone();
+    let session_id_for_registry = session_id.clone();
+    let state_for_task = state.clone();
+
+    let abort_handle = state.task_runner.spawn(async move {
+        execute_task(
+            request_id,
+            session_id,
+            tool,
+            input,
+            cwd,
+            tx,
+            state_for_task,
+            app_handle,
+            was_queued,
+            redelivered,
+            attempts,
+            slot_reserved,
+        )
+        .await;
+    });
+
+    state.register_task_handle(
+        request_id_for_registry,
+        session_id_for_registry,
+        abort_handle,
+    );
+}
+
 async fn execute_task(
     request_id: String,
     session_id: String,
@@ -366,8 +805,17 @@ async fn execute_task(
     state: Arc<AppState>,
     app_handle: AppHandle,
     was_queued: bool,
+    redelivered: bool,
+    attempts: u32,
+    slot_reserved: bool,
 ) {
-    state.increment_active_commands();
+    // A queue worker already reserved this slot (atomically, before its
+    // throttle delay) for a dequeued task; only claim a fresh one here for
+    // the immediate-dispatch path that skips the queue entirely
+    if !slot_reserved {
+        state.increment_active_commands();
+    }
+    state.mark_running(&request_id);
     state.set_status(ConnectionStatus::Working);
     state.log_info(format!(
         "Executing: {} [{} active, {} queued]",
@@ -377,8 +825,99 @@ async fn execute_task(
     ));
     emit_status_update(&app_handle, &state);
 
-    // Execute the tool
-    let result = execute_tool(&tool, &input, &cwd).await;
+    // Execute the tool, forwarding any incrementally-produced output to the
+    // server as it arrives rather than waiting for the final result
+    let forwarder = if tool == "run_command" {
+        Some(spawn_output_forwarder(
+            state.open_output_channel(&request_id),
+            tx.clone(),
+        ))
+    } else {
+        None
+    };
+
+    let scope = state.settings.read().scope.clone();
+    let result = execute_tool(&tool, &input, &cwd, &scope, &request_id, &state).await;
+
+    state.close_output_channel(&request_id);
+    if let Some(forwarder) = forwarder {
+        let _ = forwarder.await;
+    }
+
+    // The task ran to completion on its own; no longer cancellable
+    state.remove_task_handle(&request_id);
+
+    let working_directory = state.settings.read().working_directory.clone();
+
+    // A queued `run_command` that failed gets a capped-exponential-backoff
+    // retry rather than an immediate final answer, since queue admission
+    // implies the caller already expected some delay
+    let retryable = was_queued && tool == "run_command" && result.error.is_some();
+
+    if retryable && attempts + 1 < MAX_TASK_ATTEMPTS {
+        let retry_task = QueuedTask {
+            request_id: request_id.clone(),
+            session_id: session_id.clone(),
+            tool: tool.clone(),
+            input,
+            cwd,
+            queued_at: chrono::Utc::now(),
+            redelivered,
+            attempts: attempts + 1,
+            next_attempt_at: Some(chrono::Utc::now() + retry_backoff(attempts)),
+            priority: 0,
+        };
+
+        state.log_warning(format!(
+            "Retrying {} (attempt {}/{}) after failure: {}",
+            tool,
+            attempts + 1,
+            MAX_TASK_ATTEMPTS,
+            result.error.unwrap_or_default()
+        ));
+
+        queue_store::persist_task(&working_directory, &retry_task);
+        state.enqueue_task(retry_task);
+        state.mark_requeued(&request_id);
+
+        state.decrement_active_commands();
+        // A slot just freed up; wake a queue worker so it can claim it
+        // (the retry we just enqueued, or whatever else is waiting).
+        state.queue_notify.notify_one();
+        if state.get_active_commands() == 0 && *state.is_connected.read() {
+            state.set_status(ConnectionStatus::Connected);
+        }
+        emit_status_update(&app_handle, &state);
+        return;
+    }
+
+    // Only tasks that went through the queue were persisted to disk; drop
+    // the record now that it has completed (successfully, or after
+    // exhausting its retries)
+    if was_queued && result.error.is_none() {
+        queue_store::remove_task(&working_directory, &request_id);
+    } else if retryable {
+        let reason = result.error.clone().unwrap_or_default();
+        let dead_task = QueuedTask {
+            request_id: request_id.clone(),
+            session_id: session_id.clone(),
+            tool: tool.clone(),
+            input,
+            cwd,
+            queued_at: chrono::Utc::now(),
+            redelivered,
+            attempts: attempts + 1,
+            next_attempt_at: None,
+            priority: 0,
+        };
+        state.log_error(format!(
+            "{} exceeded {} attempts, dead-lettered: {}",
+            tool, MAX_TASK_ATTEMPTS, reason
+        ));
+        queue_store::append_dead_letter(&working_directory, &dead_task, &reason);
+    }
+
+    state.complete_request(&request_id, false);
 
     // Send response
     let response = OutgoingMessage::ToolResponse {
@@ -389,6 +928,8 @@ async fn execute_task(
         metrics: result.metrics,
         queued: if was_queued { Some(true) } else { None },
         queue_position: if was_queued { Some(0) } else { None },
+        redelivered,
+        retry_after_ms: None,
     };
 
     if let Ok(json) = serde_json::to_string(&response) {
@@ -396,6 +937,9 @@ async fn execute_task(
     }
 
     state.decrement_active_commands();
+    // A slot just freed up; wake a queue worker so the next queued task
+    // doesn't wait for the fallback poll to notice.
+    state.queue_notify.notify_one();
 
     if result.error.is_some() {
         state.log_error(format!(
@@ -419,48 +963,102 @@ async fn execute_task(
     }
 
     emit_status_update(&app_handle, &state);
-
-    // Process queue
-    process_queue(tx, state, app_handle);
 }
 
-fn process_queue(
+/// A persistent worker owning shard `home` of `state.task_queue`. Pops its
+/// own shard first and steals from the others once it runs dry, reserving
+/// an `active_commands` slot atomically before every dispatch so occupancy
+/// stays derived from real dequeues rather than a counter any call site can
+/// poke. Runs for the lifetime of the connection, woken by `queue_notify`
+/// whenever a task is enqueued or a slot frees up, with a periodic fallback
+/// poll so system load recovering on its own still gets noticed, and exits
+/// as soon as `shutdown_signal` flips so it stops admitting new work while
+/// the runner drains whatever it already dispatched.
+async fn run_queue_worker(
+    home: usize,
     tx: mpsc::Sender<WsCommand>,
     state: Arc<AppState>,
     app_handle: AppHandle,
+    mut shutdown_signal: tokio::sync::watch::Receiver<bool>,
 ) {
-    let settings = state.settings.read().clone();
-    let max_concurrent = settings.max_concurrent_commands;
+    loop {
+        if *shutdown_signal.borrow() {
+            return;
+        }
 
-    let metrics = collect_system_metrics(&state);
-    let active = state.get_active_commands();
+        tokio::select! {
+            _ = state.queue_notify.notified() => {}
+            _ = tokio::time::sleep(Duration::from_millis(QUEUE_DRAIN_MAX_MS)) => {}
+            _ = shutdown_signal.changed() => {}
+        }
 
-    if active >= max_concurrent || is_system_overloaded(&metrics) {
-        return;
-    }
+        if *shutdown_signal.borrow() {
+            return;
+        }
 
-    if let Some(task) = state.dequeue_task() {
-        let wait_time = chrono::Utc::now()
-            .signed_duration_since(task.queued_at)
-            .num_milliseconds();
+        // Drain as much as this worker can take on right now before going
+        // back to sleep, rather than waking once per task.
+        loop {
+            let settings = state.settings.read().clone();
+            let max_concurrent = settings.max_concurrent_commands;
+            let metrics = collect_system_metrics(&state);
 
-        state.log_info(format!("Dequeued: {} (waited {}ms)", task.tool, wait_time));
+            if is_system_overloaded(&metrics, &settings) {
+                break;
+            }
 
-        // Spawn task execution in background
-        tauri::async_runtime::spawn(async move {
-            execute_task(
+            // Hold the dispatch back briefly when the host is running hot, so
+            // the agent doesn't always race straight up to
+            // `max_concurrent_commands` regardless of what else is competing
+            // for the CPU. Done before reserving a slot, so there is no
+            // `.await` between the reservation below and the dispatch it
+            // guards - an abort landing in that gap would otherwise leak a
+            // reserved slot that nothing ever dispatches or releases.
+            let delay = state.throttle.next_delay(
+                metrics.cpu_percent,
+                settings.target_cpu_percent,
+                settings.max_delay_ms,
+            );
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+
+            // Reserve the slot now, synchronously, so two workers racing the
+            // same throttle delay can't both pass a stale `active_commands`
+            // check and overrun `max_concurrent_commands`
+            if !state.try_reserve_active_slot(max_concurrent) {
+                break;
+            }
+
+            let Some(task) = state.dequeue_task_for(home) else {
+                // Nothing left for this worker to take (even after
+                // stealing); release the slot we reserved up front.
+                state.decrement_active_commands();
+                break;
+            };
+
+            let wait_time = chrono::Utc::now()
+                .signed_duration_since(task.queued_at)
+                .num_milliseconds();
+
+            state.log_info(format!("Dequeued: {} (waited {}ms)", task.tool, wait_time));
+
+            let redelivered = task.redelivered;
+            spawn_execute_task(
                 task.request_id,
                 task.session_id,
                 task.tool,
                 task.input,
                 task.cwd,
-                tx,
-                state,
-                app_handle,
+                tx.clone(),
+                state.clone(),
+                app_handle.clone(),
                 true,
-            )
-            .await;
-        });
+                redelivered,
+                task.attempts,
+                true,
+            );
+        }
     }
 }
 